@@ -1,6 +1,12 @@
+use std::collections::BTreeMap;
 use std::error;
+use std::path::Path;
+use std::time::Duration;
 
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::hostname;
 
 macro_rules! config_defaults {
     ($($name:ident -> $type:ty: $value:expr;)*) => {
@@ -16,54 +22,752 @@ config_defaults! {
     default_general_prefix -> String: "regite".to_string();
     default_general_graphite_connection_type -> GraphiteConnectionType: GraphiteConnectionType::Tcp;
     default_general_graphite_address -> String: "localhost:2003".to_string();
+    default_general_graphite_max_udp_payload -> usize: 1400;
+    default_general_timeout -> u64: 0;
+    default_general_spool_max_size -> u64: 64 * 1024 * 1024;
+    default_general_spool_max_age -> u64: 24 * 60 * 60;
+    default_general_file_max_size -> u64: 64 * 1024 * 1024;
+    default_general_file_max_backups -> u32: 5;
 }
 
 #[derive(Debug, Default, Deserialize)]
 pub struct Config {
     pub general: General,
     #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub backend: Vec<Destination>,
+    #[serde(default)]
     pub job: Vec<Job>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+impl Config {
+    // where a job's metrics go: its own destination when it has one, otherwise every backend, or
+    // the graphite server from the general section when there are none
+    pub fn destinations(&self, job: &Job) -> Vec<Destination> {
+        match (&job.graphite_connection_type, &job.graphite_address) {
+            (None, None) if !self.backend.is_empty() => self.backend.clone(),
+            _ => vec![job.destination(&self.general)],
+        }
+    }
+
+    // whether any metrics go to stdout, where log lines would get mixed in with them
+    pub fn writes_to_stdout(&self) -> bool {
+        self.job
+            .iter()
+            .flat_map(|job| self.destinations(job))
+            .chain(self.backend.iter().cloned())
+            .any(|destination| destination.connection_type == GraphiteConnectionType::Stdout)
+            || self.general.graphite_connection_type == GraphiteConnectionType::Stdout
+    }
+}
+
+// files pulled in through include only contribute jobs
+#[derive(Deserialize)]
+struct IncludedConfig {
+    general: Option<toml::Value>,
+    backend: Option<toml::Value>,
+    include: Option<toml::Value>,
+    #[serde(default)]
+    job: Vec<Job>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct General {
     #[serde(default = "default_general_prefix")]
     pub prefix: String,
-    pub hostname: String,
+    pub hostname: Option<String>,
+    pub hostname_format: Option<HostnameFormat>,
+    pub hostname_dot_replacement: Option<String>,
     #[serde(default = "default_general_graphite_connection_type")]
     pub graphite_connection_type: GraphiteConnectionType,
     #[serde(default = "default_general_graphite_address")]
     pub graphite_address: String,
+    #[serde(default = "default_general_graphite_max_udp_payload")]
+    pub graphite_max_udp_payload: usize,
+    #[serde(default = "default_general_timeout")]
+    pub timeout: u64,
+    pub spool_dir: Option<String>,
+    #[serde(default = "default_general_spool_max_size")]
+    pub spool_max_size: u64,
+    #[serde(default = "default_general_spool_max_age")]
+    pub spool_max_age: u64,
+    #[serde(default)]
+    pub splay: u64,
+    #[serde(default)]
+    pub splay_every_run: bool,
+    #[serde(default)]
+    pub file_format: FileFormat,
+    #[serde(default = "default_general_file_max_size")]
+    pub file_max_size: u64,
+    #[serde(default = "default_general_file_max_backups")]
+    pub file_max_backups: u32,
+}
+
+// the same defaults as when the section leaves them out
+impl Default for General {
+    fn default() -> General {
+        General {
+            prefix: default_general_prefix(),
+            hostname: None,
+            hostname_format: None,
+            hostname_dot_replacement: None,
+            graphite_connection_type: default_general_graphite_connection_type(),
+            graphite_address: default_general_graphite_address(),
+            graphite_max_udp_payload: default_general_graphite_max_udp_payload(),
+            timeout: default_general_timeout(),
+            spool_dir: None,
+            spool_max_size: default_general_spool_max_size(),
+            spool_max_age: default_general_spool_max_age(),
+            splay: 0,
+            splay_every_run: false,
+            file_format: Default::default(),
+            file_max_size: default_general_file_max_size(),
+            file_max_backups: default_general_file_max_backups(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+impl General {
+    pub fn destination(&self) -> Destination {
+        Destination {
+            connection_type: self.graphite_connection_type,
+            address: self.graphite_address.clone(),
+        }
+    }
+
+    // the configured hostname, or the one of this machine, formatted for use in the prefix. a
+    // configured one is used as written unless a format is set, a detected one is shortened so
+    // the domain doesn't add levels to the prefix
+    pub fn hostname(&self) -> String {
+        let (hostname, format) = match (&self.hostname, self.hostname_format) {
+            (Some(hostname), format) => (hostname.as_str(), format.unwrap_or(HostnameFormat::Fqdn)),
+            (None, Some(HostnameFormat::Short) | None) => {
+                (hostname::system_hostname(), HostnameFormat::Short)
+            }
+            (None, Some(format)) => (hostname::system_fqdn(), format),
+        };
+        hostname::format(hostname, format, self.hostname_dot_replacement.as_deref())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum HostnameFormat {
+    Fqdn,
+    Short,
+    Reversed,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
 pub enum GraphiteConnectionType {
+    #[default]
     Tcp,
     Udp,
+    Pickle,
+    StatsD,
+    InfluxUdp,
+    InfluxHttp,
+    Prometheus,
+    OpenTsdb,
+    File,
+    Stdout,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum FileFormat {
+    #[default]
+    Plaintext,
+    Json,
 }
 
-impl Default for GraphiteConnectionType {
-    fn default() -> GraphiteConnectionType {
-        GraphiteConnectionType::Tcp
+// where a job's metrics are sent, with the same keys as in [general] when read as a [[backend]]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
+pub struct Destination {
+    #[serde(default, rename = "graphite_connection_type")]
+    pub connection_type: GraphiteConnectionType,
+    #[serde(rename = "graphite_address")]
+    pub address: String,
+}
+
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let connection_type = format!("{:?}", self.connection_type).to_lowercase();
+        write!(f, "{}://{}", connection_type, self.address)
     }
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum SanitizePolicy {
+    #[default]
+    Replace,
+    Drop,
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum ScheduleTimezone {
+    #[default]
+    Local,
+    Utc,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 pub struct Job {
     pub name: String,
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub omit_hostname: bool,
+    pub graphite_connection_type: Option<GraphiteConnectionType>,
+    pub graphite_address: Option<String>,
+    #[serde(default)]
     pub interval: u64,
+    pub schedule: Option<String>,
+    #[serde(default)]
+    pub timezone: ScheduleTimezone,
+    #[serde(default)]
+    pub align: bool,
+    #[serde(default)]
+    pub align_offset: u64,
+    pub splay: Option<u64>,
+    pub splay_every_run: Option<bool>,
     pub command: String,
     pub regex: String,
     pub output: Vec<Output>,
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub sanitize: SanitizePolicy,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub location: Option<Location>,
 }
 
-#[derive(Clone, Debug, Default, Deserialize)]
+impl Job {
+    // compares everything but where the job was defined, so moving it around isn't a change
+    pub fn same_as(&self, other: &Job) -> bool {
+        let without_location = |job: &Job| Job {
+            location: None,
+            ..job.clone()
+        };
+        without_location(self) == without_location(other)
+    }
+
+    // the prefix without the hostname, either the job's own or the general one
+    pub fn base_prefix<'a>(&'a self, general: &'a General) -> &'a str {
+        self.prefix.as_deref().unwrap_or(&general.prefix)
+    }
+
+    pub fn prefix(&self, general: &General) -> String {
+        match (self.omit_hostname, self.base_prefix(general)) {
+            (true, prefix) => prefix.to_owned(),
+            (false, "") => general.hostname(),
+            (false, prefix) => format!("{}.{}", prefix, general.hostname()),
+        }
+    }
+
+    pub fn destination(&self, general: &General) -> Destination {
+        Destination {
+            connection_type: self
+                .graphite_connection_type
+                .unwrap_or(general.graphite_connection_type),
+            address: self
+                .graphite_address
+                .clone()
+                .unwrap_or_else(|| general.graphite_address.clone()),
+        }
+    }
+
+    pub fn timeout(&self, general: &General) -> Option<Duration> {
+        // a timeout of zero disables it
+        match self.timeout.unwrap_or(general.timeout) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum MetricType {
+    #[default]
+    Gauge,
+    Counter,
+    Timer,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum InvalidValuePolicy {
+    #[default]
+    Drop,
+    Zero,
+    Fail,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 pub struct Output {
     pub name: String,
     pub value: String,
+    #[serde(default)]
+    pub strip_suffixes: Vec<String>,
+    #[serde(default)]
+    pub on_invalid: InvalidValuePolicy,
+    #[serde(default, rename = "type")]
+    pub metric_type: MetricType,
+    // values are templates, like the name
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+// finds the line of each [[job]] header, which toml doesn't keep track of
+fn job_lines(contents: &str) -> Vec<usize> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            line.trim()
+                .strip_prefix("[[")
+                .and_then(|line| line.split("]]").next())
+                .is_some_and(|table| table.trim() == "job")
+        })
+        .map(|(i, _)| i + 1)
+        .collect()
+}
+
+// only these values are expanded, everything else like regexes, output templates and tags is
+// taken literally. each is a key in a section, or in every table of an array of them
+const INTERPOLATED_KEYS: [(&str, &str); 5] = [
+    ("general", "hostname"),
+    ("general", "graphite_address"),
+    ("backend", "graphite_address"),
+    ("job", "command"),
+    ("job", "graphite_address"),
+];
+
+type EnvLookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+// expands ${VAR} and ${VAR:-default} in a string, with $${ for a literal ${. the default is also
+// used when the variable is set but empty, like in the shell
+fn interpolate_str(value: &str, env: EnvLookup, unset: &mut Vec<String>) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        result.push_str(&rest[..start]);
+
+        let expr = &rest[start + 2..end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        match (env(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => result.push_str(default),
+            (Some(value), _) => result.push_str(&value),
+            (None, Some(default)) => result.push_str(default),
+            (None, None) => {
+                if !unset.iter().any(|unset| unset == name) {
+                    unset.push(name.to_owned());
+                }
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+fn interpolate(value: &mut toml::Value, env: EnvLookup, unset: &mut Vec<String>) {
+    for (section, key) in INTERPOLATED_KEYS {
+        let tables = match value.get_mut(section) {
+            Some(toml::Value::Array(tables)) => tables.iter_mut().collect(),
+            Some(table) => vec![table],
+            None => vec![],
+        };
+        for table in tables {
+            if let Some(toml::Value::String(s)) = table.get_mut(key) {
+                *s = interpolate_str(s, env, unset);
+            }
+        }
+    }
+}
+
+fn parse<T: DeserializeOwned>(contents: &str, env: EnvLookup) -> Result<T, Box<dyn error::Error>> {
+    let mut value: toml::Value = toml::from_str(contents)?;
+
+    let mut unset = vec![];
+    interpolate(&mut value, env, &mut unset);
+    if !unset.is_empty() {
+        return Err(format!("Unset environment variables: {}", unset.join(", ")).into());
+    }
+
+    Ok(value.try_into()?)
+}
+
+fn set_locations(jobs: &mut [Job], file_path: &str, contents: &str) {
+    for (job, line) in jobs.iter_mut().zip(job_lines(contents)) {
+        job.location = Some(Location {
+            file: file_path.to_owned(),
+            line,
+        });
+    }
+}
+
+fn load_included(file_path: &str) -> Result<Vec<Job>, Box<dyn error::Error>> {
+    let file_contents = std::fs::read_to_string(file_path)?;
+    let mut included: IncludedConfig = parse(&file_contents, &env_var)?;
+    if included.general.is_some() {
+        return Err("[general] is only allowed in the main config file".into());
+    }
+    if included.backend.is_some() {
+        return Err("[[backend]] is only allowed in the main config file".into());
+    }
+    // includes aren't followed any further, so one here would be silently ignored
+    if included.include.is_some() {
+        return Err("include is only allowed in the main config file".into());
+    }
+
+    set_locations(&mut included.job, file_path, &file_contents);
+    Ok(included.job)
 }
 
-pub fn load_config(file_path: &str) -> Result<Config, Box<dyn error::Error>> {
+// loads the main config file along with the jobs of every file it includes, and of every .toml
+// file in config_dir. relative include patterns are resolved against the main file's directory
+pub fn load_config(
+    file_path: &str,
+    config_dir: Option<&str>,
+) -> Result<Config, Box<dyn error::Error>> {
     let file_contents = std::fs::read_to_string(file_path)?;
-    Ok(toml::from_str(&file_contents)?)
+    let mut config: Config = parse(&file_contents, &env_var)?;
+    set_locations(&mut config.job, file_path, &file_contents);
+
+    let base = Path::new(file_path)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let mut patterns: Vec<_> = config.include.iter().map(|p| base.join(p)).collect();
+    if let Some(config_dir) = config_dir {
+        patterns.push(Path::new(config_dir).join("*.toml"));
+    }
+
+    for pattern in patterns {
+        let pattern = pattern.to_string_lossy();
+        let paths = glob::glob(&pattern).map_err(|e| format!("Include {}: {}", pattern, e))?;
+        for path in paths {
+            let path = path?.to_string_lossy().into_owned();
+            let jobs = load_included(&path).map_err(|e| format!("{}: {}", path, e))?;
+            config.job.extend(jobs);
+        }
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_job_lines() {
+        let contents = "[general]\nhostname = \"host\"\n\n[[job]]\nname = \"a\"\n[[job.output]]\n  [[ job ]] # b\n";
+        assert_eq!(vec![4, 7], job_lines(contents));
+    }
+
+    #[test]
+    fn test_general_default() {
+        let config: Config = parse("[general]\n", &|_| None).unwrap();
+        assert_eq!(General::default(), config.general);
+    }
+
+    fn test_env(name: &str) -> Option<String> {
+        match name {
+            "SET" => Some("set".to_string()),
+            "EMPTY" => Some(String::new()),
+            "HOST" => Some("host".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate_str() {
+        let mut unset = vec![];
+        assert_eq!(
+            "a set b  c default d ${SET} ${x",
+            interpolate_str(
+                "a ${SET} b ${EMPTY} c ${EMPTY:-default} d $${SET} ${x",
+                &test_env,
+                &mut unset
+            )
+        );
+        assert!(unset.is_empty());
+
+        assert_eq!(
+            "fallback ",
+            interpolate_str(
+                "${UNSET:-fallback} ${UNSET}${OTHER}${UNSET}",
+                &test_env,
+                &mut unset
+            )
+        );
+        assert_eq!(vec!["UNSET", "OTHER"], unset);
+    }
+
+    #[test]
+    fn test_parse_interpolates() {
+        let config: Config = parse(
+            "[general]\nhostname = \"${HOST}\"\nprefix = \"${HOST}\"\n\
+             [[job]]\nname = \"a\"\ncommand = \"echo ${HOST}\"\nregex = \"^${HOST}$\"\n\
+             graphite_address = \"${HOST}:2003\"\ntags = { hostname = \"${host}\" }\n\
+             [[job.output]]\nname = \"${value}\"\nvalue = \"${value}\"\n\
+             tags = { hostname = \"${host}\" }\n",
+            &test_env,
+        )
+        .unwrap();
+        assert_eq!(Some("host".to_string()), config.general.hostname);
+        assert_eq!("${HOST}", config.general.prefix);
+        assert_eq!("echo host", config.job[0].command);
+        assert_eq!("^${HOST}$", config.job[0].regex);
+        assert_eq!("${value}", config.job[0].output[0].name);
+        assert_eq!(Some("host:2003"), config.job[0].graphite_address.as_deref());
+        // a tag that happens to be called hostname is a template, not an environment variable
+        assert_eq!("${host}", config.job[0].tags["hostname"]);
+        assert_eq!("${host}", config.job[0].output[0].tags["hostname"]);
+
+        let error = parse::<Config>("[general]\nhostname = \"${MISSING}\"\n", &test_env)
+            .unwrap_err()
+            .to_string();
+        assert_eq!("Unset environment variables: MISSING", error);
+    }
+
+    #[test]
+    fn test_hostname() {
+        let mut general = General {
+            hostname: Some("web1.example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!("web1.example.com", general.hostname());
+        general.hostname_format = Some(HostnameFormat::Short);
+        assert_eq!("web1", general.hostname());
+
+        // a detected hostname doesn't add levels to the prefix unless asked to
+        general.hostname = None;
+        general.hostname_format = None;
+        assert!(!general.hostname().contains('.'));
+    }
+
+    #[test]
+    fn test_prefix_and_destination() {
+        let general = General {
+            prefix: "regite".to_string(),
+            hostname: Some("host".to_string()),
+            graphite_address: "carbon:2003".to_string(),
+            ..Default::default()
+        };
+        let job = Job::default();
+        assert_eq!("regite.host", job.prefix(&general));
+        assert_eq!(general.destination(), job.destination(&general));
+
+        let job = Job {
+            prefix: Some("apps".to_string()),
+            omit_hostname: true,
+            graphite_connection_type: Some(GraphiteConnectionType::Udp),
+            ..Default::default()
+        };
+        assert_eq!("apps", job.prefix(&general));
+        assert_eq!(
+            Destination {
+                connection_type: GraphiteConnectionType::Udp,
+                address: "carbon:2003".to_string(),
+            },
+            job.destination(&general)
+        );
+
+        let job = Job {
+            prefix: Some("".to_string()),
+            ..Default::default()
+        };
+        assert_eq!("host", job.prefix(&general));
+    }
+
+    #[test]
+    fn test_destinations() {
+        let backend = |address: &str| Destination {
+            connection_type: GraphiteConnectionType::Tcp,
+            address: address.to_string(),
+        };
+        let mut config = Config {
+            general: General {
+                graphite_address: "carbon:2003".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let job = Job::default();
+        let routed = Job {
+            graphite_address: Some("apps:2003".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(vec![backend("carbon:2003")], config.destinations(&job));
+
+        config.backend = vec![backend("old:2003"), backend("new:2003")];
+        assert_eq!(config.backend, config.destinations(&job));
+        assert_eq!(vec![backend("apps:2003")], config.destinations(&routed));
+        assert_eq!("tcp://old:2003", config.backend[0].to_string());
+
+        let config: Config = parse(
+            "[general]\n[[backend]]\ngraphite_connection_type = \"Udp\"\n\
+             graphite_address = \"${HOST}:2003\"\n",
+            &test_env,
+        )
+        .unwrap();
+        assert_eq!("udp://host:2003", config.backend[0].to_string());
+    }
+
+    #[test]
+    fn test_writes_to_stdout() {
+        let mut config = Config::default();
+        assert!(!config.writes_to_stdout());
+
+        config.job = vec![Job {
+            graphite_connection_type: Some(GraphiteConnectionType::Stdout),
+            ..Default::default()
+        }];
+        assert!(config.writes_to_stdout());
+
+        config.job.clear();
+        config.backend = vec![Destination {
+            connection_type: GraphiteConnectionType::Stdout,
+            address: String::new(),
+        }];
+        assert!(config.writes_to_stdout());
+
+        config.backend.clear();
+        config.general.graphite_connection_type = GraphiteConnectionType::Stdout;
+        assert!(config.writes_to_stdout());
+    }
+
+    #[test]
+    fn test_same_as() {
+        let job = Job {
+            name: "a".to_string(),
+            location: Some(Location {
+                file: "regite.toml".to_string(),
+                line: 1,
+            }),
+            ..Default::default()
+        };
+        let moved = Job {
+            location: None,
+            ..job.clone()
+        };
+        let changed = Job {
+            interval: 10,
+            ..job.clone()
+        };
+
+        assert!(job.same_as(&moved));
+        assert!(!job.same_as(&changed));
+    }
+
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("regite-config-test-{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        std::fs::create_dir_all(dir.join("extra")).unwrap();
+        for (file, contents) in files {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    const JOB: &str = "command = \"true\"\nregex = \"\"\noutput = []\n";
+
+    #[test]
+    fn test_include() {
+        let dir = config_dir(
+            "include",
+            &[
+                (
+                    "regite.toml",
+                    &format!("include = [\"conf.d/*.toml\"]\n[general]\nhostname = \"host\"\n[[job]]\nname = \"a\"\n{}", JOB),
+                ),
+                ("conf.d/b.toml", &format!("\n[[job]]\nname = \"b\"\n{}", JOB)),
+                ("conf.d/c.toml", &format!("[[job]]\nname = \"c\"\n{}", JOB)),
+                ("extra/d.toml", &format!("[[job]]\nname = \"d\"\n{}", JOB)),
+            ],
+        );
+        let main = dir.join("regite.toml");
+
+        let config = load_config(main.to_str().unwrap(), None).unwrap();
+        let names: Vec<_> = config.job.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(vec!["a", "b", "c"], names);
+        let location = config.job[1].location.as_ref().unwrap();
+        assert!(location.file.ends_with("conf.d/b.toml"));
+        assert_eq!(2, location.line);
+
+        let config = load_config(main.to_str().unwrap(), dir.join("extra").to_str()).unwrap();
+        assert_eq!(4, config.job.len());
+        assert_eq!("d", config.job[3].name);
+    }
+
+    #[test]
+    fn test_include_general() {
+        let dir = config_dir(
+            "include-general",
+            &[
+                (
+                    "regite.toml",
+                    "include = [\"conf.d/*.toml\"]\n[general]\nhostname = \"host\"\n",
+                ),
+                ("conf.d/a.toml", "[general]\nhostname = \"other\"\n"),
+            ],
+        );
+
+        let error = load_config(dir.join("regite.toml").to_str().unwrap(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with("conf.d/a.toml: [general] is only allowed in the main config file"));
+    }
+    #[test]
+    fn test_nested_include() {
+        let dir = config_dir(
+            "include-nested",
+            &[
+                ("regite.toml", "include = [\"conf.d/*.toml\"]\n[general]\n"),
+                ("conf.d/a.toml", "include = [\"more/*.toml\"]\n"),
+            ],
+        );
+
+        let error = load_config(dir.join("regite.toml").to_str().unwrap(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with("conf.d/a.toml: include is only allowed in the main config file"));
+    }
 }
@@ -1,14 +1,40 @@
-use std::io;
-use std::process;
+use std::io::{self, Read};
+use std::process::{self, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecutorErrorKind {
+    Failed,
+    TimedOut,
+}
 
 #[derive(Debug, PartialEq)]
 pub struct ExecutorError {
+    kind: ExecutorErrorKind,
     msg: String,
 }
 
 impl ExecutorError {
     fn new<S: Into<String>>(msg: S) -> ExecutorError {
-        ExecutorError { msg: msg.into() }
+        ExecutorError {
+            kind: ExecutorErrorKind::Failed,
+            msg: msg.into(),
+        }
+    }
+
+    fn timed_out(timeout: Duration) -> ExecutorError {
+        ExecutorError {
+            kind: ExecutorErrorKind::TimedOut,
+            msg: format!("Killed after timeout of {:?}", timeout),
+        }
+    }
+
+    pub fn kind(&self) -> ExecutorErrorKind {
+        self.kind
     }
 }
 
@@ -26,14 +52,15 @@ pub trait Executor: Send {
 
 struct ExecutorImpl<F>
 where
-    F: Fn(&str, &[String]) -> io::Result<process::Output> + Send,
+    F: Fn(&str, &[String], Option<Duration>) -> io::Result<process::Output> + Send,
 {
     execute_fn: F,
+    timeout: Option<Duration>,
 }
 
 impl<F> Executor for ExecutorImpl<F>
 where
-    F: Fn(&str, &[String]) -> io::Result<process::Output> + Send,
+    F: Fn(&str, &[String], Option<Duration>) -> io::Result<process::Output> + Send,
 {
     fn execute(&self, command: &str) -> Result<String, ExecutorError> {
         let parts = match shlex::split(command) {
@@ -45,19 +72,108 @@ where
             return Err(ExecutorError::new("No command specified"));
         }
 
-        match (self.execute_fn)(&parts[0], &parts[1..]) {
+        match (self.execute_fn)(&parts[0], &parts[1..], self.timeout) {
             Ok(output) => match output.status.success() {
                 true => Ok(String::from_utf8(output.stdout).unwrap()),
                 false => Err(ExecutorError::new("Failure exit code")),
             },
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                Err(ExecutorError::timed_out(self.timeout.unwrap_or_default()))
+            }
             Err(e) => Err(ExecutorError::new(format!("IO Error: {}", e))),
         }
     }
 }
 
-pub fn build() -> Box<dyn Executor> {
+// the pipe only closes once everything holding it has exited, which includes anything the
+// command left running in the background, so the result is received rather than joined
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> Receiver<Vec<u8>> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        let _ = tx.send(buf);
+    });
+    rx
+}
+
+#[cfg(unix)]
+fn kill(child: &mut process::Child) {
+    // the child leads its own process group, so this also takes out anything it spawned
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut process::Child) {
+    let _ = child.kill();
+}
+
+fn run_command(
+    cmd: &str,
+    args: &[String],
+    timeout: Option<Duration>,
+) -> io::Result<process::Output> {
+    let mut command = process::Command::new(cmd);
+    command.args(args);
+
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return command.output(),
+    };
+
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+    let mut child = command.spawn()?;
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            kill(&mut child);
+            child.wait()?;
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    // a command that exited but left something behind holding its output is still running as
+    // far as the timeout is concerned
+    let read = |pipe: Receiver<Vec<u8>>| {
+        pipe.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .ok()
+    };
+    match (read(stdout), read(stderr)) {
+        (Some(stdout), Some(stderr)) => Ok(process::Output {
+            status,
+            stdout,
+            stderr,
+        }),
+        _ => {
+            kill(&mut child);
+            Err(io::Error::from(io::ErrorKind::TimedOut))
+        }
+    }
+}
+
+pub fn build(timeout: Option<Duration>) -> Box<dyn Executor> {
     Box::new(ExecutorImpl {
-        execute_fn: |cmd: &str, args: &[String]| process::Command::new(cmd).args(args).output(),
+        execute_fn: run_command,
+        timeout,
     })
 }
 
@@ -72,7 +188,8 @@ mod test {
     #[test]
     fn test_input_invalid() {
         let executor = ExecutorImpl {
-            execute_fn: |_, _| Err(io::Error::from(io::ErrorKind::Unsupported)),
+            execute_fn: |_, _, _| Err(io::Error::from(io::ErrorKind::Unsupported)),
+            timeout: None,
         };
 
         assert_eq!(
@@ -89,7 +206,8 @@ mod test {
     #[test]
     fn test_io_error() {
         let executor = ExecutorImpl {
-            execute_fn: |_, _| Err(io::Error::from(io::ErrorKind::Unsupported)),
+            execute_fn: |_, _, _| Err(io::Error::from(io::ErrorKind::Unsupported)),
+            timeout: None,
         };
 
         assert_eq!(
@@ -101,7 +219,7 @@ mod test {
     #[test]
     fn test_failed_exit_code() {
         let executor = ExecutorImpl {
-            execute_fn: |cmd, args| {
+            execute_fn: |cmd, args, _| {
                 assert_eq!("command", cmd);
                 assert_eq!(Vec::<String>::new(), args);
                 Ok(process::Output {
@@ -110,6 +228,7 @@ mod test {
                     stderr: vec![],
                 })
             },
+            timeout: None,
         };
 
         assert_eq!(
@@ -121,7 +240,7 @@ mod test {
     #[test]
     fn test_success() {
         let executor = ExecutorImpl {
-            execute_fn: |cmd, args| {
+            execute_fn: |cmd, args, _| {
                 assert_eq!("command", cmd);
                 assert_eq!(vec!["arg1", "arg2"], args);
                 Ok(process::Output {
@@ -130,8 +249,68 @@ mod test {
                     stderr: vec![],
                 })
             },
+            timeout: None,
         };
 
         assert_eq!("output", executor.execute("command arg1 arg2").unwrap());
     }
+
+    #[test]
+    fn test_timed_out() {
+        let executor = ExecutorImpl {
+            execute_fn: |_, _, timeout| {
+                assert_eq!(Some(Duration::from_secs(5)), timeout);
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            },
+            timeout: Some(Duration::from_secs(5)),
+        };
+
+        let err = executor.execute("cmd").unwrap_err();
+        assert_eq!(ExecutorErrorKind::TimedOut, err.kind());
+        assert_eq!(ExecutorError::timed_out(Duration::from_secs(5)), err);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_kills_hung_command() {
+        let start_time = Instant::now();
+        let err = run_command(
+            "/bin/bash",
+            &["-c".to_string(), "sleep 10 & sleep 10".to_string()],
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap_err();
+
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        assert!(start_time.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_background_process_holding_output() {
+        let start_time = Instant::now();
+        let err = run_command(
+            "/bin/bash",
+            &["-c".to_string(), "sleep 10 & echo output".to_string()],
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap_err();
+
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        assert!(start_time.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_command_within_timeout() {
+        let output = run_command(
+            "/bin/bash",
+            &["-c".to_string(), "echo output".to_string()],
+            Some(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(b"output\n".to_vec(), output.stdout);
+    }
 }
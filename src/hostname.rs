@@ -0,0 +1,121 @@
+use std::ffi::{CStr, CString};
+use std::sync::OnceLock;
+
+use crate::config::HostnameFormat;
+
+fn gethostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, and zeroed so the result is terminated
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() - 1) };
+    if result != 0 {
+        return None;
+    }
+
+    let name = CStr::from_bytes_until_nul(&buf).ok()?.to_str().ok()?;
+    match name.is_empty() {
+        true => None,
+        false => Some(name.to_owned()),
+    }
+}
+
+fn proc_hostname() -> Option<String> {
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    match name.trim() {
+        "" => None,
+        name => Some(name.to_owned()),
+    }
+}
+
+// asks the resolver for the canonical name, which is how `hostname -f` finds the domain
+fn canonical_name(hostname: &str) -> Option<String> {
+    let node = CString::new(hostname).ok()?;
+    // SAFETY: addrinfo is plain data, for which all zeroes is a valid value
+    let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+    hints.ai_family = libc::AF_UNSPEC;
+    hints.ai_flags = libc::AI_CANONNAME;
+
+    let mut info = std::ptr::null_mut();
+    // SAFETY: all pointers are valid, and info is only read on success and freed afterwards
+    unsafe {
+        if libc::getaddrinfo(node.as_ptr(), std::ptr::null(), &hints, &mut info) != 0 {
+            return None;
+        }
+        let name = match (*info).ai_canonname.is_null() {
+            true => None,
+            false => CStr::from_ptr((*info).ai_canonname)
+                .to_str()
+                .ok()
+                .map(str::to_owned),
+        };
+        libc::freeaddrinfo(info);
+        name
+    }
+}
+
+// the name of this machine, looked up once per process
+pub fn system_hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| match gethostname().or_else(proc_hostname) {
+        Some(hostname) => hostname,
+        None => {
+            log::warn!("Unable to determine the hostname, using localhost");
+            "localhost".to_string()
+        }
+    })
+}
+
+// the name of this machine as the fqdn when it can be resolved. getaddrinfo can block for as long
+// as the resolver takes, so this only runs for formats that need the domain, and once per process
+// so a reload doesn't wait on it again
+pub fn system_fqdn() -> &'static str {
+    static FQDN: OnceLock<String> = OnceLock::new();
+    FQDN.get_or_init(|| {
+        let hostname = system_hostname();
+        match hostname.contains('.') {
+            true => hostname.to_owned(),
+            false => canonical_name(hostname)
+                .filter(|name| name.starts_with(&format!("{}.", hostname)))
+                .unwrap_or_else(|| hostname.to_owned()),
+        }
+    })
+}
+
+pub fn format(hostname: &str, format: HostnameFormat, dot_replacement: Option<&str>) -> String {
+    let hostname = match format {
+        HostnameFormat::Fqdn => hostname.to_owned(),
+        HostnameFormat::Short => hostname.split('.').next().unwrap_or_default().to_owned(),
+        HostnameFormat::Reversed => hostname.rsplit('.').collect::<Vec<_>>().join("."),
+    };
+
+    match dot_replacement {
+        Some(replacement) => hostname.replace('.', replacement),
+        None => hostname,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let hostname = "web1.dc1.example.com";
+        assert_eq!(hostname, format(hostname, HostnameFormat::Fqdn, None));
+        assert_eq!("web1", format(hostname, HostnameFormat::Short, None));
+        assert_eq!(
+            "com.example.dc1.web1",
+            format(hostname, HostnameFormat::Reversed, None)
+        );
+        assert_eq!(
+            "web1_dc1_example_com",
+            format(hostname, HostnameFormat::Fqdn, Some("_"))
+        );
+        assert_eq!("web1", format("web1", HostnameFormat::Reversed, Some("_")));
+    }
+
+    #[test]
+    fn test_system_hostname() {
+        assert!(!system_hostname().is_empty());
+        assert!(system_fqdn().starts_with(system_hostname()));
+    }
+}
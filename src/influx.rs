@@ -0,0 +1,212 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::metric::{self, Metric};
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_measurement(value: &str) -> String {
+    escape(value, &[',', ' '])
+}
+
+fn escape_key(value: &str) -> String {
+    escape(value, &[',', '=', ' '])
+}
+
+// one line per job, tag set and timestamp, with every output as a field of it. the job is the
+// measurement, and the output's name without the prefix the field. the host is a tag unless the
+// output sets one itself
+pub fn lines(metrics: &[Metric]) -> Vec<String> {
+    let mut lines: Vec<(&Metric, Vec<(&str, &str)>)> = vec![];
+    for metric in metrics {
+        let field = match metric.path.is_empty() {
+            true => metric.name.as_str(),
+            false => metric.path.as_str(),
+        };
+
+        let line = lines.iter_mut().find(|(first, _)| {
+            first.job == metric.job
+                && first.host == metric.host
+                && first.tags == metric.tags
+                && first.timestamp == metric.timestamp
+        });
+        match line {
+            // a point has one value per field, so like graphite the last one wins
+            Some((_, fields)) => match fields.iter_mut().find(|(key, _)| *key == field) {
+                Some(existing) => existing.1 = &metric.raw,
+                None => fields.push((field, &metric.raw)),
+            },
+            None => lines.push((metric, vec![(field, &metric.raw)])),
+        }
+    }
+
+    lines
+        .into_iter()
+        .map(|(metric, fields)| {
+            let mut line = match metric.job.is_empty() {
+                true => "regite".to_string(),
+                false => escape_measurement(&metric.job),
+            };
+            if !metric.host.is_empty() && !metric.tags.iter().any(|(key, _)| key == "host") {
+                line.push_str(&format!(",host={}", escape_key(&metric.host)));
+            }
+            for (key, value) in &metric.tags {
+                line.push_str(&format!(",{}={}", escape_key(key), escape_key(value)));
+            }
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|(key, value)| format!("{}={}", escape_key(key), value))
+                .collect();
+            format!(
+                "{} {} {}000000000",
+                line,
+                fields.join(","),
+                metric.timestamp
+            )
+        })
+        .collect()
+}
+
+// the address of the write endpoint is host:port/path?query, with an optional http:// in front
+fn split_url(url: &str) -> io::Result<(&str, &str)> {
+    let url = url.strip_prefix("http://").unwrap_or(url);
+    if url.contains("://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported scheme in {}", url),
+        ));
+    }
+
+    Ok(match url.find('/') {
+        Some(i) => (&url[..i], &url[i..]),
+        None => (url, "/write"),
+    })
+}
+
+pub fn post(url: &str, body: &str) -> io::Result<()> {
+    let (host, path) = split_url(url)?;
+    let mut stream = metric::connect(host)?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "Unexpected response from {}: {}",
+            host, status
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::metric;
+    use std::io::{BufRead, BufReader};
+    use std::net;
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(
+            vec![
+                "disk,mount=/ used=10,free=90 123000000000",
+                "disk,mount=/mnt/my\\ data used=20 123000000000",
+                "load\\,avg one=0.5 123000000000",
+            ],
+            lines(&[
+                metric("disk", "used", 10.0, &[("mount", "/")]),
+                metric("disk", "used", 20.0, &[("mount", "/mnt/my data")]),
+                metric("disk", "free", 90.0, &[("mount", "/")]),
+                metric("load,avg", "one", 0.5, &[]),
+            ])
+        );
+
+        let host = |path: &str, value: f64, tags: &[(&str, &str)]| Metric {
+            host: "web1".to_string(),
+            ..metric("disk", path, value, tags)
+        };
+        assert_eq!(
+            vec![
+                "disk,host=web1 used=30,free=70 123000000000",
+                "disk,host=other used=1 123000000000",
+            ],
+            lines(&[
+                host("used", 10.0, &[]),
+                host("free", 70.0, &[]),
+                host("used", 30.0, &[]),
+                host("used", 1.0, &[("host", "other")]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_split_url() {
+        assert_eq!(
+            ("localhost:8086", "/write?db=metrics"),
+            split_url("http://localhost:8086/write?db=metrics").unwrap()
+        );
+        assert_eq!(
+            ("localhost:8086", "/write"),
+            split_url("localhost:8086").unwrap()
+        );
+        assert!(split_url("https://localhost:8086").is_err());
+    }
+
+    #[test]
+    fn test_post() {
+        let listener = net::TcpListener::bind("localhost:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            (request_line, String::from_utf8(body).unwrap())
+        });
+
+        post(&format!("{}/write?db=metrics", address), "line 1\n").unwrap();
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!("POST /write?db=metrics HTTP/1.1\r\n", request_line);
+        assert_eq!("line 1\n", body);
+    }
+}
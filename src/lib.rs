@@ -1,74 +1,272 @@
 #![warn(clippy::all)]
 
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::error;
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
 
 pub mod config;
 mod executor;
+mod hostname;
+mod influx;
 pub mod logging;
 mod metric;
 mod parser;
+mod pickle;
+mod prometheus;
 mod runner;
+mod sanitizer;
+mod schedule;
+mod sink;
+mod spool;
+mod task;
+mod template;
+#[cfg(test)]
+mod test_util;
+pub mod validate;
+
+pub use task::JobMatch;
+
+type Reporters = HashMap<config::Destination, Arc<dyn metric::MetricReporter>>;
 
 pub struct Regite {
-    runners: Vec<runner::Runner>,
+    general: config::General,
+    backends: Vec<config::Destination>,
+    metrics: Reporters,
+    runners: Vec<(config::Job, runner::Runner)>,
+}
+
+// jobs sending to the same destination share a reporter, and with it the connection and spool
+fn reporter_for(
+    metrics: &mut Reporters,
+    job: &config::Job,
+    config: &config::Config,
+) -> Arc<dyn metric::MetricReporter> {
+    let general = &config.general;
+    let mut reporters: Vec<_> = config
+        .destinations(job)
+        .into_iter()
+        .map(|destination| {
+            let reporter = metrics
+                .entry(destination.clone())
+                .or_insert_with(|| {
+                    spool::build(metric::build(&destination, general), &destination, general)
+                })
+                .clone();
+            (destination.to_string(), reporter)
+        })
+        .collect();
+
+    match reporters.len() {
+        1 => reporters.pop().unwrap().1,
+        _ => Arc::new(metric::FanOutReporter::new(reporters)),
+    }
+}
+
+fn build_runner(
+    job: &config::Job,
+    general: &config::General,
+    metrics: Arc<dyn metric::MetricReporter>,
+) -> Result<runner::Runner, Box<dyn error::Error>> {
+    let task = task::Task::new(job, general)?;
+    Ok(runner::Runner::new(
+        job.name.clone(),
+        schedule::build(job, general)?,
+        Box::new(move |scheduled_time| {
+            log::info!("Running task");
+            if let Err(e) = task.run(scheduled_time, metrics.as_ref()) {
+                log::error!("Error: {}", e);
+            }
+            log::info!("Finished running task");
+        }),
+    ))
 }
 
 impl Regite {
-    pub fn new(config: config::Config) -> Regite {
-        let prefix = format!("{}.{}", config.general.prefix, config.general.hostname);
-        let mut runners = Vec::with_capacity(config.job.len());
-
-        for job in &config.job {
-            let command = job.command.clone();
-            let executor = executor::build();
-            let parser = parser::Parser::new(&prefix, &job.regex, &job.output);
-            let metrics = metric::build(&config.general);
-            runners.push(runner::Runner::new(
-                job.name.clone(),
-                Duration::from_secs(job.interval),
-                Box::new(move || {
-                    log::info!("Running task");
-                    let start_time = SystemTime::now();
-                    let output = match executor.execute(&command) {
-                        Ok(output) => output,
-                        Err(e) => {
-                            log::error!("Error: {:?}", e);
-                            return;
-                        }
-                    };
-
-                    let epoch_time = start_time
-                        .duration_since(UNIX_EPOCH)
-                        .expect("Time went backwards")
-                        .as_secs();
-                    for (name, value) in parser.parse(&output) {
-                        if let Err(e) = metrics.report(&name, &value, epoch_time) {
-                            log::error!("Error: {:?}", e);
-                        }
+    pub fn new(mut config: config::Config) -> Result<Regite, Box<dyn error::Error>> {
+        let mut metrics = HashMap::new();
+        let mut runners = vec![];
+        for job in std::mem::take(&mut config.job) {
+            let reporter = reporter_for(&mut metrics, &job, &config);
+            match build_runner(&job, &config.general, reporter) {
+                Ok(runner) => runners.push((job, runner)),
+                Err(e) => return Err(format!("Job {}: {}", job.name, e).into()),
+            }
+        }
+
+        Ok(Regite {
+            general: config.general,
+            backends: config.backend,
+            metrics,
+            runners,
+        })
+    }
+
+    // swaps in a new config while running, only touching the jobs that were added, removed or
+    // changed. an invalid config is rejected as a whole and the current one keeps running
+    pub fn reload(&mut self, mut config: config::Config) -> Result<(), Box<dyn error::Error>> {
+        let problems = validate::validate(&config);
+        if !problems.is_empty() {
+            let problems: Vec<_> = problems.iter().map(|p| p.to_string()).collect();
+            return Err(format!("Invalid config: {}", problems.join("; ")).into());
+        }
+
+        // everything depends on the general section and backends, so changing them restarts every
+        // job
+        let general_changed = config.general != self.general || config.backend != self.backends;
+        let mut metrics = match general_changed {
+            true => HashMap::new(),
+            false => self.metrics.clone(),
+        };
+
+        let mut current = std::mem::take(&mut self.runners);
+        let mut kept = vec![];
+        let mut fresh = vec![];
+        for job in std::mem::take(&mut config.job) {
+            match current.iter().position(|(old, _)| old.name == job.name) {
+                Some(i) if !general_changed && current[i].0.same_as(&job) => {
+                    let (_, runner) = current.swap_remove(i);
+                    kept.push((job, runner));
+                }
+                _ => match build_runner(
+                    &job,
+                    &config.general,
+                    reporter_for(&mut metrics, &job, &config),
+                ) {
+                    Ok(runner) => fresh.push((job, runner)),
+                    Err(e) => {
+                        current.extend(kept);
+                        self.runners = current;
+                        return Err(format!("Job {}: {}", job.name, e).into());
                     }
+                },
+            }
+        }
 
-                    log::info!("Finished running task");
-                }),
-            ));
+        // whatever is left over was removed or changed, a run in progress is allowed to finish
+        let mut stopped = Vec::with_capacity(current.len());
+        for (job, runner) in current {
+            match fresh.iter().any(|(new, _)| new.name == job.name) {
+                true => log::info!("Restarting changed job {}", job.name),
+                false => log::info!("Stopping removed job {}", job.name),
+            }
+            runner.stop();
+            stopped.push((job, runner));
         }
 
-        Regite { runners }
+        for (job, mut runner) in fresh {
+            if !stopped.iter().any(|(old, _)| old.name == job.name) {
+                log::info!("Starting new job {}", job.name);
+            }
+            runner.start();
+            kept.push((job, runner));
+        }
+
+        for (job, mut runner) in stopped {
+            runner.join();
+            // backends that keep the latest metrics would go on serving those of a removed job
+            if !kept.iter().any(|(new, _)| new.name == job.name) {
+                for reporter in self.metrics.values() {
+                    reporter.forget(&job.name);
+                }
+            }
+        }
+
+        self.runners = kept;
+        metrics.retain(|destination, _| {
+            self.runners
+                .iter()
+                .any(|(job, _)| config.destinations(job).contains(destination))
+        });
+        self.general = config.general;
+        self.backends = config.backend;
+        self.metrics = metrics;
+        Ok(())
+    }
+
+    pub fn test_job(
+        config: &config::Config,
+        name: &str,
+        input: Option<&str>,
+    ) -> Result<Vec<JobMatch>, Box<dyn error::Error>> {
+        let job = match config.job.iter().find(|job| job.name == name) {
+            Some(job) => job,
+            None => return Err(format!("No job named \"{}\"", name).into()),
+        };
+
+        Ok(task::Task::new(job, &config.general)?.dry_run(input)?)
+    }
+
+    // runs the given jobs (or all of them) a single time in parallel, failing if any of them did
+    pub fn run_once(
+        config: &config::Config,
+        names: &[String],
+    ) -> Result<(), Box<dyn error::Error>> {
+        if let Some(name) = names
+            .iter()
+            .find(|name| !config.job.iter().any(|job| &job.name == *name))
+        {
+            return Err(format!("No job named \"{}\"", name).into());
+        }
+
+        let mut metrics = HashMap::new();
+        let tasks = config
+            .job
+            .iter()
+            .filter(|job| names.is_empty() || names.contains(&job.name))
+            .map(|job| {
+                let task = task::Task::new(job, &config.general)
+                    .map_err(|e| format!("Job {}: {}", job.name, e))?;
+                Ok((job, task, reporter_for(&mut metrics, job, config)))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let handles: Vec<_> = tasks
+            .into_iter()
+            .map(|(job, task, metrics)| {
+                thread::Builder::new()
+                    .name(job.name.clone())
+                    .spawn(move || {
+                        log::info!("Running task");
+                        let result = task.run(SystemTime::now(), metrics.as_ref());
+                        match &result {
+                            Ok(()) => log::info!("Finished running task"),
+                            Err(e) => log::error!("Error: {}", e),
+                        }
+                        result.is_ok()
+                    })
+                    .expect("Couldn't spawn thread")
+            })
+            .collect();
+
+        let total = handles.len();
+        let failed = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or(false))
+            .filter(|ok| !ok)
+            .count();
+
+        match failed {
+            0 => Ok(()),
+            failed => Err(format!("{} of {} jobs failed", failed, total).into()),
+        }
     }
 
     pub fn start(&mut self) {
-        for runner in &mut self.runners {
+        for (_, runner) in &mut self.runners {
             runner.start();
         }
     }
 
     pub fn stop(&mut self) {
-        for runner in &mut self.runners {
+        for (_, runner) in &mut self.runners {
             runner.stop();
         }
     }
 
     pub fn join(&mut self) {
-        for runner in &mut self.runners {
+        for (_, runner) in &mut self.runners {
             runner.join();
         }
     }
@@ -2,7 +2,10 @@ use log::{Level, Log, Metadata, Record};
 
 const CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
 
-struct ConsoleLogger {}
+struct ConsoleLogger {
+    // keeps stdout free for output that's meant to be piped
+    stderr_only: bool,
+}
 
 impl Log for ConsoleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
@@ -19,8 +22,8 @@ impl Log for ConsoleLogger {
                 record.level(),
                 record.args()
             );
-            match metadata.level() {
-                Level::Error | Level::Warn => eprintln!("{}", msg),
+            match (metadata.level(), self.stderr_only) {
+                (Level::Error | Level::Warn, _) | (_, true) => eprintln!("{}", msg),
                 _ => println!("{}", msg),
             }
         }
@@ -29,7 +32,8 @@ impl Log for ConsoleLogger {
     fn flush(&self) {}
 }
 
-pub fn init_logging() {
-    log::set_boxed_logger(Box::new(ConsoleLogger {})).expect("Unable to initialize logger");
+pub fn init_logging(stderr_only: bool) {
+    log::set_boxed_logger(Box::new(ConsoleLogger { stderr_only }))
+        .expect("Unable to initialize logger");
     log::set_max_level(log::LevelFilter::max());
 }
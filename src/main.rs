@@ -1,7 +1,9 @@
 #![warn(clippy::all)]
 
-use std::sync::{Arc, Condvar, Mutex};
+use std::io::{self, Read};
 
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -18,35 +20,179 @@ struct Args {
         default_value = "/etc/regite.toml"
     )]
     pub config: String,
+
+    #[structopt(
+        long,
+        value_name = "DIR",
+        help = "Also loads the jobs of every .toml file in this directory"
+    )]
+    pub config_dir: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Runs every job once, or only the named ones, and exits instead of scheduling them"
+    )]
+    pub once: bool,
+
+    #[structopt(
+        long = "job",
+        value_name = "NAME",
+        requires = "once",
+        number_of_values = 1,
+        help = "Limits --once to the named job, can be given multiple times"
+    )]
+    pub jobs: Vec<String>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
 }
 
-fn main() {
-    regite::logging::init_logging();
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(about = "Runs a single job and prints what it would send, without sending it")]
+    TestJob {
+        #[structopt(help = "The name of the job to run")]
+        name: String,
 
+        #[structopt(
+            short,
+            long,
+            value_name = "FILE",
+            help = "Parses the contents of a file instead of running the command, - for stdin"
+        )]
+        input: Option<String>,
+    },
+
+    #[structopt(about = "Checks the config file and reports every problem found")]
+    Validate,
+}
+
+fn test_job(config: &regite::config::Config, name: &str, input: Option<&str>) {
+    let read = match input {
+        Some("-") => {
+            let mut buf = String::new();
+            io::stdin().read_to_string(&mut buf).map(|_| Some(buf))
+        }
+        Some(path) => std::fs::read_to_string(path).map(Some),
+        None => Ok(None),
+    };
+    let input = match (read, input) {
+        (Ok(input), _) => input,
+        (Err(e), Some("-")) => {
+            eprintln!("Unable to read stdin: {}", e);
+            std::process::exit(1);
+        }
+        (Err(e), path) => {
+            eprintln!("Unable to read {}: {}", path.unwrap_or_default(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let matches = match regite::Regite::test_job(config, name, input.as_deref()) {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // only the lines go to stdout so they can be piped, the rest is for the reader
+    if matches.is_empty() {
+        eprintln!("The regex didn't match anything");
+    }
+
+    for job_match in matches {
+        eprintln!("Match {:?}", job_match.text);
+        for line in &job_match.lines {
+            println!("{}", line);
+        }
+        match (&job_match.problem, job_match.lines.is_empty()) {
+            (Some(problem), true) => eprintln!("    (produced nothing: {})", problem),
+            (Some(problem), false) => eprintln!("    ({})", problem),
+            (None, true) => eprintln!("    (produced nothing)"),
+            (None, false) => {}
+        }
+    }
+}
+
+fn print_problems(problems: &[regite::validate::Problem]) {
+    for problem in problems {
+        eprintln!("{}", problem);
+    }
+    eprintln!("Found {} problems", problems.len());
+}
+
+fn main() {
     let args = Args::from_args();
-    log::info!("Loading config from {}", args.config);
-    let config = regite::config::load_config(&args.config).unwrap();
+
+    if let Some(Command::Validate) = &args.command {
+        let problems = regite::validate::validate_file(&args.config, args.config_dir.as_deref());
+        if !problems.is_empty() {
+            print_problems(&problems);
+            std::process::exit(1);
+        }
+        println!("{} is valid", args.config);
+        return;
+    }
+
+    let config = match regite::config::load_config(&args.config, args.config_dir.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Unable to load {}: {}", args.config, e);
+            std::process::exit(1);
+        }
+    };
+    // the logger can only be set once, so it waits for the config to know if stdout is taken
+    regite::logging::init_logging(
+        matches!(args.command, Some(Command::TestJob { .. })) || config.writes_to_stdout(),
+    );
+    log::info!("Loaded config from {}", args.config);
+
+    let problems = regite::validate::validate(&config);
+    if !problems.is_empty() {
+        print_problems(&problems);
+        std::process::exit(1);
+    }
+
+    if let Some(Command::TestJob { name, input }) = &args.command {
+        test_job(&config, name, input.as_deref());
+        return;
+    }
+
+    if args.once {
+        if let Err(e) = regite::Regite::run_once(&config, &args.jobs) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
     log::info!("Starting background threads...");
-    let mut regite = regite::Regite::new(config);
+    let mut regite = match regite::Regite::new(config) {
+        Ok(regite) => regite,
+        Err(e) => {
+            eprintln!("Unable to start: {}", e);
+            std::process::exit(1);
+        }
+    };
     regite.start();
     log::info!("Background threads started");
 
-    #[allow(clippy::mutex_atomic)]
-    let condvar_pair = Arc::new((Mutex::new(true), Condvar::new()));
-    let condvar_pair_clone = condvar_pair.clone();
-    ctrlc::set_handler(move || {
-        let (lock, cvar) = &*condvar_pair_clone;
-        let mut pending = lock.lock().unwrap();
-        *pending = false;
-        cvar.notify_one();
-    })
-    .expect("Error setting ctrl-c handler");
-
-    let (lock, cvar) = &*condvar_pair;
-    let _guard = cvar
-        .wait_while(lock.lock().unwrap(), |pending| *pending)
-        .unwrap();
+    let mut signals =
+        Signals::new([SIGINT, SIGTERM, SIGHUP]).expect("Error setting signal handlers");
+    for signal in signals.forever() {
+        if signal != SIGHUP {
+            break;
+        }
+
+        log::info!("Reloading config from {}", args.config);
+        let result = regite::config::load_config(&args.config, args.config_dir.as_deref())
+            .and_then(|config| regite.reload(config));
+        match result {
+            Ok(()) => log::info!("Reloaded config"),
+            Err(e) => log::error!("Keeping the current config, unable to reload: {}", e),
+        }
+    }
 
     log::info!("Stopping background threads...");
     regite.stop();
@@ -1,7 +1,16 @@
-use std::io::{self, Write};
-use std::net;
+use std::io::{self, Read, Write};
+use std::net::{self, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
 
 use crate::config;
+use crate::influx;
+use crate::pickle;
+use crate::prometheus;
+use crate::sink;
 
 #[derive(Debug, PartialEq)]
 pub struct MetricReporterError {
@@ -9,7 +18,7 @@ pub struct MetricReporterError {
 }
 
 impl MetricReporterError {
-    fn new<S: Into<String>>(msg: S) -> MetricReporterError {
+    pub fn new<S: Into<String>>(msg: S) -> MetricReporterError {
         MetricReporterError { msg: msg.into() }
     }
 }
@@ -22,67 +31,854 @@ impl std::fmt::Display for MetricReporterError {
 
 impl std::error::Error for MetricReporterError {}
 
-pub trait MetricReporter: Send {
-    fn report(&self, name: &str, value: &str, timestamp: u64) -> Result<(), MetricReporterError>;
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Metric {
+    pub name: String,
+    pub value: f64,
+    // the value as the command printed it, which is what text protocols send so that large
+    // counters keep their precision
+    pub raw: String,
+    pub timestamp: u64,
+    pub metric_type: config::MetricType,
+    pub tags: Vec<(String, String)>,
+    // the job that produced it, and its name without the prefix
+    pub job: String,
+    pub path: String,
+    // the job's prefix without the hostname and the hostname, for backends that tag it rather
+    // than put it in the path. the hostname is empty when the job omits it
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub host: String,
+}
+
+impl Metric {
+    pub fn new<S: Into<String>>(name: S, value: f64, timestamp: u64) -> Metric {
+        Metric {
+            name: name.into(),
+            value,
+            raw: value.to_string(),
+            timestamp,
+            metric_type: config::MetricType::Gauge,
+            tags: vec![],
+            job: String::new(),
+            path: String::new(),
+            prefix: String::new(),
+            host: String::new(),
+        }
+    }
 }
 
+impl std::fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.name, self.raw, self.timestamp)
+    }
+}
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait MetricReporter: Send + Sync {
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError>;
+
+    // drops what is kept of a job's metrics, for backends that serve the latest ones instead of
+    // sending them on
+    fn forget(&self, _job: &str) {}
+}
+
+type SendFn = Box<dyn Fn(&str) -> io::Result<()> + Send + Sync>;
+type FormatFn = Box<dyn Fn(&[Metric]) -> Vec<String> + Send + Sync>;
+
 struct MetricReporterImpl {
-    send_fn: Box<dyn Fn(&str) -> io::Result<()> + Send>,
+    send_fn: SendFn,
+    format_fn: FormatFn,
+    max_payload: Option<usize>,
+}
+
+impl MetricReporterImpl {
+    fn payloads(&self, metrics: &[Metric]) -> Vec<String> {
+        let mut payloads = vec![];
+        let mut payload = String::new();
+        for line in (self.format_fn)(metrics) {
+            let line = format!("{}\n", line);
+            if let Some(max_payload) = self.max_payload {
+                if !payload.is_empty() && payload.len() + line.len() > max_payload {
+                    payloads.push(std::mem::take(&mut payload));
+                }
+            }
+            payload.push_str(&line);
+        }
+
+        if !payload.is_empty() {
+            payloads.push(payload);
+        }
+        payloads
+    }
 }
 
 impl MetricReporter for MetricReporterImpl {
-    fn report(&self, name: &str, value: &str, timestamp: u64) -> Result<(), MetricReporterError> {
-        let metric = format!("{} {} {}\n", name, value, timestamp);
-        match (self.send_fn)(&metric) {
-            Ok(()) => Ok(()),
-            Err(e) => Err(MetricReporterError::new(format!("IO Error: {}", e))),
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError> {
+        for payload in self.payloads(metrics) {
+            if let Err(e) = (self.send_fn)(&payload) {
+                return Err(MetricReporterError::new(format!("IO Error: {}", e)));
+            }
         }
+        Ok(())
     }
 }
 
-pub fn build(config: &config::General) -> Box<dyn MetricReporter> {
-    let address = config.graphite_address.clone();
-    Box::new(MetricReporterImpl {
-        send_fn: match config.graphite_connection_type {
-            config::GraphiteConnectionType::Udp => {
-                let socket =
-                    net::UdpSocket::bind("[::]:0").expect("Unable to bind to ephemeral port");
-                Box::new(move |s| socket.send_to(s.as_bytes(), &address).and(Ok(())))
+// sends every batch to all of its reporters, so one failing doesn't hold back the others
+pub struct FanOutReporter {
+    reporters: Vec<(String, Arc<dyn MetricReporter>)>,
+}
+
+impl FanOutReporter {
+    pub fn new(reporters: Vec<(String, Arc<dyn MetricReporter>)>) -> FanOutReporter {
+        FanOutReporter { reporters }
+    }
+}
+
+impl MetricReporter for FanOutReporter {
+    // every backend gets its own thread, so a slow one doesn't hold up the others. they're named
+    // after the job's thread so their logs still show which job it was
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError> {
+        let job = thread::current().name().unwrap_or_default().to_owned();
+        let results: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .reporters
+                .iter()
+                .map(|(name, reporter)| {
+                    let handle = thread::Builder::new()
+                        .name(job.clone())
+                        .spawn_scoped(scope, move || reporter.report_batch(metrics))
+                        .expect("Couldn't spawn thread");
+                    (name, handle)
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|(name, handle)| (name, handle.join().expect("Couldn't join thread")))
+                .collect()
+        });
+
+        let mut failed = vec![];
+        for (name, result) in results {
+            if let Err(e) = result {
+                log::error!("Unable to report to {}: {}", name, e);
+                failed.push(name.as_str());
             }
-            config::GraphiteConnectionType::Tcp => Box::new(move |s| {
-                net::TcpStream::connect(&address)?
-                    .write(s.as_bytes())
-                    .and(Ok(()))
+        }
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(MetricReporterError::new(format!(
+                "Failed to report to {} of {} backends: {}",
+                failed.len(),
+                self.reporters.len(),
+                failed.join(", ")
+            ))),
+        }
+    }
+
+    fn forget(&self, job: &str) {
+        for (_, reporter) in &self.reporters {
+            reporter.forget(job);
+        }
+    }
+}
+
+struct ConnectionState {
+    stream: Option<net::TcpStream>,
+    connecting: bool,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+struct TcpConnection {
+    address: String,
+    // whether the server answers what it's sent, rather than never writing back like carbon
+    replies: bool,
+    state: Mutex<ConnectionState>,
+}
+
+impl TcpConnection {
+    fn new(address: String) -> TcpConnection {
+        TcpConnection {
+            address,
+            replies: false,
+            state: Mutex::new(ConnectionState {
+                stream: None,
+                connecting: false,
+                backoff: INITIAL_BACKOFF,
+                retry_at: None,
             }),
+        }
+    }
+
+    fn with_replies(self) -> TcpConnection {
+        TcpConnection {
+            replies: true,
+            ..self
+        }
+    }
+
+    fn send(&self, payload: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(stream) = &mut state.stream {
+            let result = match self.replies {
+                true => drain_replies(stream, &self.address),
+                false => check_open(stream),
+            };
+            if let Err(e) = result {
+                log::warn!("Lost connection to {}: {}", self.address, e);
+                state.stream = None;
+            }
+        }
+
+        let reused = state.stream.is_some();
+        if !reused {
+            state = self.connect(state)?;
+        }
+
+        match state.stream.as_mut().unwrap().write_all(payload) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                log::warn!("Lost connection to {}: {}", self.address, e);
+                state.stream = None;
+                if !reused {
+                    return Err(e);
+                }
+
+                // the connection may have gone stale since it was last used, so give a fresh one
+                // a single chance before giving up on this write
+                state = self.connect(state)?;
+                state.stream.as_mut().unwrap().write_all(payload)
+            }
+        }
+    }
+
+    // connects without holding the lock, so an unreachable server only holds up the job that
+    // happens to be connecting, while the others fail right away
+    fn connect<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, ConnectionState>,
+    ) -> io::Result<MutexGuard<'a, ConnectionState>> {
+        if state.connecting {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("Not connected to {}, connecting", self.address),
+            ));
+        }
+        if let Some(retry_at) = state.retry_at {
+            let now = Instant::now();
+            if now < retry_at {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!(
+                        "Not connected to {}, retrying in {:?}",
+                        self.address,
+                        retry_at - now
+                    ),
+                ));
+            }
+        }
+
+        state.connecting = true;
+        drop(state);
+        let result = self.open_stream();
+        let mut state = self.state.lock().unwrap();
+        state.connecting = false;
+
+        match result {
+            Ok(stream) => {
+                log::info!("Connected to {}", self.address);
+                state.stream = Some(stream);
+                state.backoff = INITIAL_BACKOFF;
+                state.retry_at = None;
+                Ok(state)
+            }
+            Err(e) => {
+                log::warn!(
+                    "Unable to connect to {}: {}, retrying in {:?}",
+                    self.address,
+                    e,
+                    state.backoff
+                );
+                state.retry_at = Some(Instant::now() + state.backoff);
+                state.backoff = std::cmp::min(state.backoff * 2, MAX_BACKOFF);
+                Err(e)
+            }
+        }
+    }
+
+    fn open_stream(&self) -> io::Result<net::TcpStream> {
+        let stream = connect(&self.address)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(stream)
+    }
+}
+
+// tries every address the name resolves to, as a plain connect would but without waiting forever
+pub(crate) fn connect(address: &str) -> io::Result<net::TcpStream> {
+    let mut last_error = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("No addresses found for {}", address),
+    );
+    for address in address.to_socket_addrs()? {
+        match net::TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn graphite_lines(metrics: &[Metric]) -> Vec<String> {
+    metrics.iter().map(Metric::to_string).collect()
+}
+
+fn statsd_lines(metrics: &[Metric]) -> Vec<String> {
+    metrics.iter().map(statsd_line).collect()
+}
+
+// the characters that separate the parts of a dogstatsd line can't appear in a name or tag
+fn statsd_escape(value: &str, special: &str) -> String {
+    value
+        .chars()
+        .map(|c| match special.contains(c) || c.is_whitespace() {
+            true => '_',
+            false => c,
+        })
+        .collect()
+}
+
+fn statsd_name(name: &str) -> String {
+    statsd_escape(name, ":|@#")
+}
+
+fn statsd_tag(value: &str) -> String {
+    statsd_escape(value, ",|:#")
+}
+
+// statsd has no timestamps, it aggregates by time of arrival. tags use the dogstatsd extension
+fn statsd_line(metric: &Metric) -> String {
+    let metric_type = match metric.metric_type {
+        config::MetricType::Gauge => "g",
+        config::MetricType::Counter => "c",
+        config::MetricType::Timer => "ms",
+    };
+    let mut tags = String::new();
+    if !metric.tags.is_empty() {
+        let pairs: Vec<_> = metric
+            .tags
+            .iter()
+            .map(|(key, value)| format!("{}:{}", statsd_tag(key), statsd_tag(value)))
+            .collect();
+        tags = format!("|#{}", pairs.join(","));
+    }
+    let line = |value: &str| {
+        format!(
+            "{}:{}|{}{}",
+            statsd_name(&metric.name),
+            value,
+            metric_type,
+            tags
+        )
+    };
+
+    // a gauge with a sign is changed by the value instead of set to it, so a negative one is set
+    // to zero first, and both go in the same payload
+    match metric.metric_type {
+        config::MetricType::Gauge => {
+            let value = metric.raw.strip_prefix('+').unwrap_or(&metric.raw);
+            match value.starts_with('-') {
+                true => format!("{}\n{}", line("0"), line(value)),
+                false => line(value),
+            }
+        }
+        _ => line(&metric.raw),
+    }
+}
+
+// opentsdb takes letters, digits and -_./ in metric names as well as in tags
+fn opentsdb_tag(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c.is_alphanumeric() || "-_./".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+// the hostname is a tag rather than part of the metric, so the prefix is the job's without it
+fn opentsdb_line(metric: &Metric) -> String {
+    let name = match (metric.prefix.as_str(), metric.path.is_empty()) {
+        (_, true) => metric.name.clone(),
+        ("", false) => metric.path.clone(),
+        (prefix, false) => format!("{}.{}", prefix, metric.path),
+    };
+
+    let mut line = format!(
+        "put {} {} {}",
+        opentsdb_tag(&name),
+        metric.timestamp,
+        metric.raw
+    );
+    if !metric.host.is_empty() {
+        line.push_str(&format!(" host={}", opentsdb_tag(&metric.host)));
+    }
+    if !metric.job.is_empty() {
+        line.push_str(&format!(" job={}", opentsdb_tag(&metric.job)));
+    }
+    for (key, value) in &metric.tags {
+        line.push_str(&format!(" {}={}", opentsdb_tag(key), opentsdb_tag(value)));
+    }
+    line
+}
+
+const PICKLE_BATCH_SIZE: usize = 500;
+
+struct PickleReporter {
+    connection: TcpConnection,
+}
+
+impl MetricReporter for PickleReporter {
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError> {
+        for chunk in metrics.chunks(PICKLE_BATCH_SIZE) {
+            self.connection
+                .send(&pickle::encode(chunk))
+                .map_err(|e| MetricReporterError::new(format!("IO Error: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+// carbon never writes back, so a readable socket means the other end has hung up
+#[cfg(unix)]
+fn check_open(stream: &net::TcpStream) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut buf = [0u8; 1];
+    // SAFETY: the buffer is valid for its whole length, and the peek leaves the socket untouched
+    let result = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    match result {
+        0 => Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        result if result > 0 => Ok(()),
+        _ => match io::Error::last_os_error() {
+            e if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            e => Err(e),
+        },
+    }
+}
+
+// opentsdb answers a bad put with an error line. the replies are read and logged before the next
+// write, so they neither fill up the buffer nor hide that the other end has hung up
+fn drain_replies(stream: &mut net::TcpStream, address: &str) -> io::Result<()> {
+    stream.set_nonblocking(true)?;
+    let mut replies = vec![];
+    let mut buf = [0u8; 4096];
+    let result = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+            Ok(len) => replies.extend_from_slice(&buf[..len]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    for reply in String::from_utf8_lossy(&replies).lines() {
+        if !reply.trim().is_empty() {
+            log::warn!("{} replied: {}", address, reply.trim());
+        }
+    }
+    result.and_then(|()| stream.set_nonblocking(false))
+}
+
+#[cfg(not(unix))]
+fn check_open(stream: &net::TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(true)?;
+    let result = match stream.peek(&mut [0; 1]) {
+        Ok(0) => Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+fn udp_reporter(
+    address: String,
+    format_fn: FormatFn,
+    config: &config::General,
+) -> MetricReporterImpl {
+    let socket = net::UdpSocket::bind("[::]:0").expect("Unable to bind to ephemeral port");
+    MetricReporterImpl {
+        send_fn: Box::new(move |s| socket.send_to(s.as_bytes(), &address).and(Ok(()))),
+        format_fn,
+        // a payload of zero disables splitting
+        max_payload: match config.graphite_max_udp_payload {
+            0 => None,
+            max_payload => Some(max_payload),
+        },
+    }
+}
+
+pub fn build(
+    destination: &config::Destination,
+    config: &config::General,
+) -> Arc<dyn MetricReporter> {
+    let address = destination.address.clone();
+    Arc::new(match destination.connection_type {
+        config::GraphiteConnectionType::Udp => {
+            udp_reporter(address, Box::new(graphite_lines), config)
+        }
+        config::GraphiteConnectionType::StatsD => {
+            udp_reporter(address, Box::new(statsd_lines), config)
+        }
+        config::GraphiteConnectionType::InfluxUdp => {
+            udp_reporter(address, Box::new(influx::lines), config)
+        }
+        config::GraphiteConnectionType::InfluxHttp => MetricReporterImpl {
+            send_fn: Box::new(move |s| influx::post(&address, s)),
+            format_fn: Box::new(influx::lines),
+            max_payload: None,
         },
+        config::GraphiteConnectionType::File => {
+            return Arc::new(sink::build_file(&address, config))
+        }
+        config::GraphiteConnectionType::Stdout => return Arc::new(sink::build_stdout(config)),
+        config::GraphiteConnectionType::OpenTsdb => {
+            let connection = TcpConnection::new(address).with_replies();
+            MetricReporterImpl {
+                send_fn: Box::new(move |s| connection.send(s.as_bytes())),
+                format_fn: Box::new(|metrics| metrics.iter().map(opentsdb_line).collect()),
+                max_payload: None,
+            }
+        }
+        config::GraphiteConnectionType::Prometheus => return Arc::new(prometheus::build(&address)),
+        config::GraphiteConnectionType::Pickle => {
+            return Arc::new(PickleReporter {
+                connection: TcpConnection::new(address),
+            })
+        }
+        config::GraphiteConnectionType::Tcp => {
+            let connection = TcpConnection::new(address);
+            MetricReporterImpl {
+                send_fn: Box::new(move |s| connection.send(s.as_bytes())),
+                format_fn: Box::new(graphite_lines),
+                max_payload: None,
+            }
+        }
     })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::FakeReporter;
+
+    #[test]
+    fn test_fan_out() {
+        let reporter = |available| Arc::new(FakeReporter::new(available));
+        let (first, second, third) = (reporter(true), reporter(false), reporter(true));
+        let fan_out = FanOutReporter::new(vec![
+            ("first".to_string(), first.clone()),
+            ("second".to_string(), second.clone()),
+            ("third".to_string(), third.clone()),
+        ]);
+
+        assert_eq!(
+            Err(MetricReporterError::new(
+                "Failed to report to 1 of 3 backends: second"
+            )),
+            fan_out.report_batch(&[Metric::new("name", 1.0, 123)])
+        );
+        assert_eq!(1, first.reported.lock().unwrap().len());
+        assert!(second.reported.lock().unwrap().is_empty());
+        assert_eq!(1, third.reported.lock().unwrap().len());
+    }
 
     #[test]
     fn test_formats() {
         let reporter = MetricReporterImpl {
             send_fn: Box::new(|s| {
-                assert_eq!("name value 123\n", s);
+                assert_eq!("name 1.5 123\n", s);
                 Ok(())
             }),
+            format_fn: Box::new(graphite_lines),
+            max_payload: None,
         };
 
-        assert_eq!(Ok(()), reporter.report("name", "value", 123));
+        assert_eq!(
+            Ok(()),
+            reporter.report_batch(&[Metric::new("name", 1.5, 123)])
+        );
+    }
+
+    #[test]
+    fn test_batch_single_write() {
+        let writes = Arc::new(Mutex::new(vec![]));
+        let writes_clone = writes.clone();
+        let reporter = MetricReporterImpl {
+            send_fn: Box::new(move |s| {
+                writes_clone.lock().unwrap().push(s.to_string());
+                Ok(())
+            }),
+            format_fn: Box::new(graphite_lines),
+            max_payload: None,
+        };
+
+        assert_eq!(
+            Ok(()),
+            reporter.report_batch(&[
+                Metric::new("name1", 1.0, 123),
+                Metric::new("name2", 2.0, 123),
+                Metric::new("name3", 3.0, 123),
+            ])
+        );
+        assert_eq!(
+            vec!["name1 1 123\nname2 2 123\nname3 3 123\n"],
+            *writes.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_batch_split_payloads() {
+        let writes = Arc::new(Mutex::new(vec![]));
+        let writes_clone = writes.clone();
+        let reporter = MetricReporterImpl {
+            send_fn: Box::new(move |s| {
+                writes_clone.lock().unwrap().push(s.to_string());
+                Ok(())
+            }),
+            format_fn: Box::new(graphite_lines),
+            max_payload: Some(24),
+        };
+
+        assert_eq!(
+            Ok(()),
+            reporter.report_batch(&[
+                Metric::new("name1", 1.0, 123),
+                Metric::new("name2", 2.0, 123),
+                Metric::new("name3", 3.0, 123),
+                Metric::new("a_much_longer_name", 4.0, 123),
+            ])
+        );
+        assert_eq!(
+            vec![
+                "name1 1 123\nname2 2 123\n",
+                "name3 3 123\n",
+                "a_much_longer_name 4 123\n"
+            ],
+            *writes.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_statsd_line() {
+        assert_eq!("name:1.5|g", statsd_line(&Metric::new("name", 1.5, 123)));
+        assert_eq!(
+            "name:2|c|#mount:/mnt,env:prod",
+            statsd_line(&Metric {
+                metric_type: config::MetricType::Counter,
+                tags: vec![
+                    ("mount".to_string(), "/mnt".to_string()),
+                    ("env".to_string(), "prod".to_string())
+                ],
+                ..Metric::new("name", 2.0, 123)
+            })
+        );
+        assert_eq!(
+            "name:30|ms",
+            statsd_line(&Metric {
+                metric_type: config::MetricType::Timer,
+                ..Metric::new("name", 30.0, 123)
+            })
+        );
+        assert_eq!(
+            "name:0|g|#env:prod\nname:-3|g|#env:prod",
+            statsd_line(&Metric {
+                tags: vec![("env".to_string(), "prod".to_string())],
+                ..Metric::new("name", -3.0, 123)
+            })
+        );
+        assert_eq!(
+            "name:5|g",
+            statsd_line(&Metric {
+                raw: "+5".to_string(),
+                ..Metric::new("name", 5.0, 123)
+            })
+        );
+        assert_eq!(
+            "cpu.user_percent_a_b:1|g",
+            statsd_line(&Metric::new("cpu.user:percent@a|b", 1.0, 123))
+        );
+        assert_eq!(
+            "name:1|g|#a_b:c_d_e_f",
+            statsd_line(&Metric {
+                tags: vec![("a:b".to_string(), "c,d|e#f".to_string())],
+                ..Metric::new("name", 1.0, 123)
+            })
+        );
+    }
+
+    #[test]
+    fn test_opentsdb_line() {
+        let metric = Metric {
+            job: "disk usage".to_string(),
+            path: "disk.used".to_string(),
+            prefix: "regite".to_string(),
+            host: "web1.example.com".to_string(),
+            tags: vec![("mount".to_string(), "/mnt/my data".to_string())],
+            ..Metric::new("regite.web1.example.com.disk.used", 10.0, 123)
+        };
+        assert_eq!(
+            "put regite.disk.used 123 10 host=web1.example.com job=disk_usage mount=/mnt/my_data",
+            opentsdb_line(&metric)
+        );
+        assert_eq!(
+            "put disk.used 123 10 host=web1 job=disk_usage mount=/mnt/my_data",
+            opentsdb_line(&Metric {
+                prefix: String::new(),
+                host: "web1".to_string(),
+                ..metric.clone()
+            })
+        );
+        // a job that omits the hostname doesn't get the tag either
+        assert_eq!(
+            "put apps.disk.used 123 10 job=disk_usage mount=/mnt/my_data",
+            opentsdb_line(&Metric {
+                prefix: "apps".to_string(),
+                host: String::new(),
+                ..metric
+            })
+        );
+        assert_eq!(
+            "put name 123 1.5",
+            opentsdb_line(&Metric::new("name", 1.5, 123))
+        );
+        assert_eq!(
+            "put cpu.user_percent_x_1_a.größe 123 1.5",
+            opentsdb_line(&Metric::new("cpu.user:percent,x=1@a.größe", 1.5, 123))
+        );
     }
 
     #[test]
     fn test_io_error() {
         let reporter = MetricReporterImpl {
             send_fn: Box::new(|_| Err(io::Error::from(io::ErrorKind::Unsupported))),
+            format_fn: Box::new(graphite_lines),
+            max_payload: None,
         };
 
         assert_eq!(
             MetricReporterError::new("IO Error: unsupported"),
-            reporter.report("name", "value", 123).unwrap_err()
+            reporter
+                .report_batch(&[Metric::new("name", 1.5, 123)])
+                .unwrap_err()
         );
     }
+
+    #[test]
+    fn test_tcp_reuses_connection() {
+        let listener = net::TcpListener::bind("localhost:0").unwrap();
+        let connection = TcpConnection::new(listener.local_addr().unwrap().to_string());
+
+        assert!(connection.send(b"line1\n").is_ok());
+        assert!(connection.send(b"line2\n").is_ok());
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"line1\nline2\n", &buf);
+
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+
+    #[test]
+    fn test_tcp_reconnects() {
+        let listener = net::TcpListener::bind("localhost:0").unwrap();
+        let connection = TcpConnection::new(listener.local_addr().unwrap().to_string());
+
+        assert!(connection.send(b"line1\n").is_ok());
+        drop(listener.accept().unwrap());
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert!(connection.send(b"line2\n").is_ok());
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 6];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"line2\n", &buf);
+    }
+
+    #[test]
+    fn test_tcp_reads_replies() {
+        let listener = net::TcpListener::bind("localhost:0").unwrap();
+        let connection =
+            TcpConnection::new(listener.local_addr().unwrap().to_string()).with_replies();
+
+        // the server complains about the first line and hangs up
+        assert!(connection.send(b"line1\n").is_ok());
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 6];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(b"put: illegal argument\n").unwrap();
+        drop(stream);
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert!(connection.send(b"line2\n").is_ok());
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"line2\n", &buf);
+    }
+
+    #[test]
+    fn test_tcp_backoff() {
+        let listener = net::TcpListener::bind("localhost:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let connection = TcpConnection::new(address);
+        assert_eq!(
+            io::ErrorKind::ConnectionRefused,
+            connection.send(b"line\n").unwrap_err().kind()
+        );
+        assert_eq!(
+            io::ErrorKind::NotConnected,
+            connection.send(b"line\n").unwrap_err().kind()
+        );
+
+        let state = connection.state.lock().unwrap();
+        assert_eq!(INITIAL_BACKOFF * 2, state.backoff);
+    }
+
+    #[test]
+    fn test_tcp_connecting_elsewhere() {
+        let listener = net::TcpListener::bind("localhost:0").unwrap();
+        let connection = TcpConnection::new(listener.local_addr().unwrap().to_string());
+
+        // another job being stuck on connecting doesn't hold this one up
+        connection.state.lock().unwrap().connecting = true;
+        assert_eq!(
+            io::ErrorKind::NotConnected,
+            connection.send(b"line\n").unwrap_err().kind()
+        );
+
+        connection.state.lock().unwrap().connecting = false;
+        assert!(connection.send(b"line\n").is_ok());
+    }
 }
@@ -1,43 +1,165 @@
-use crate::config::Output;
+use crate::config::{InvalidValuePolicy, MetricType, Output};
+use crate::metric::Metric;
+use crate::template::Template;
 
-use regex::Regex;
+use regex::{Captures, Regex};
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    msg: String,
+}
+
+impl ParseError {
+    fn new<S: Into<String>>(msg: S) -> ParseError {
+        ParseError { msg: msg.into() }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Parsed {
+    // without a timestamp yet, that is up to whoever reports them
+    pub values: Vec<Metric>,
+    pub invalid: usize,
+}
+
+struct OutputTemplate {
+    name: Template,
+    value: Template,
+    strip_suffixes: Vec<String>,
+    on_invalid: InvalidValuePolicy,
+    metric_type: MetricType,
+    tags: Vec<(String, Template)>,
+}
 
 pub struct Parser {
     prefix: String,
     regex: Regex,
-    outputs: Vec<Output>,
+    outputs: Vec<OutputTemplate>,
 }
 
 impl Parser {
-    pub fn new(prefix: &str, regex: &str, outputs: &[Output]) -> Parser {
-        Parser {
+    pub fn new(prefix: &str, regex: &str, outputs: &[Output]) -> Result<Parser, ParseError> {
+        let template =
+            |template: &str| Template::parse(template).map_err(|e| ParseError::new(e.to_string()));
+
+        Ok(Parser {
             prefix: prefix.to_owned(),
-            regex: Regex::new(&format!("(?m){}", regex)).unwrap(),
-            outputs: outputs.to_owned(),
+            regex: Regex::new(&format!("(?m){}", regex))
+                .map_err(|e| ParseError::new(format!("Invalid regex: {}", e)))?,
+            outputs: outputs
+                .iter()
+                .map(|output| {
+                    Ok(OutputTemplate {
+                        name: template(&output.name)?,
+                        value: template(&output.value)?,
+                        strip_suffixes: output.strip_suffixes.clone(),
+                        on_invalid: output.on_invalid,
+                        metric_type: output.metric_type,
+                        tags: output
+                            .tags
+                            .iter()
+                            .map(|(key, value)| Ok((key.clone(), template(value)?)))
+                            .collect::<Result<_, ParseError>>()?,
+                    })
+                })
+                .collect::<Result<_, ParseError>>()?,
+        })
+    }
+
+    pub fn parse(&self, input: &str) -> Result<Parsed, ParseError> {
+        let mut results = Parsed::default();
+
+        for captures in self.regex.captures_iter(input) {
+            self.parse_captures(&captures, &mut results)?;
         }
+
+        Ok(results)
+    }
+
+    // parses each match on its own, so it can be seen which of them produced what
+    pub fn parse_matches<'a>(&self, input: &'a str) -> Vec<(&'a str, Result<Parsed, ParseError>)> {
+        self.regex
+            .captures_iter(input)
+            .map(|captures| {
+                let mut results = Parsed::default();
+                let result = self
+                    .parse_captures(&captures, &mut results)
+                    .and(Ok(results));
+                (captures.get(0).unwrap().as_str(), result)
+            })
+            .collect()
     }
 
-    pub fn parse(&self, input: &str) -> Vec<(String, String)> {
-        let mut results = vec![];
-
-        for mat in self.regex.find_iter(input) {
-            for output in &self.outputs {
-                let substring = mat.as_str();
-                results.push((
-                    format!(
-                        "{}.{}",
-                        self.prefix,
-                        self.regex.replace(substring, &output.name)
-                    ),
-                    self.regex.replace(substring, &output.value).to_string(),
-                ));
+    fn parse_captures(&self, captures: &Captures, results: &mut Parsed) -> Result<(), ParseError> {
+        for output in &self.outputs {
+            let name = match self.prefix.is_empty() {
+                true => output.name.render(captures),
+                false => format!("{}.{}", self.prefix, output.name.render(captures)),
+            };
+            let value = output.value.render(captures);
+            let metric = |value, raw: &str| Metric {
+                metric_type: output.metric_type,
+                tags: output
+                    .tags
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.render(captures)))
+                    .collect(),
+                raw: raw.to_owned(),
+                ..Metric::new(name.clone(), value, 0)
+            };
+            match to_number(&value, &output.strip_suffixes) {
+                Some((number, raw)) => results.values.push(metric(number, raw)),
+                None => {
+                    results.invalid += 1;
+                    match output.on_invalid {
+                        InvalidValuePolicy::Drop => {
+                            log::warn!("Invalid value \"{}\" for {}", value, name)
+                        }
+                        InvalidValuePolicy::Zero => {
+                            log::warn!("Invalid value \"{}\" for {}", value, name);
+                            results.values.push(metric(0.0, "0"));
+                        }
+                        // the error is logged along with the failed run
+                        InvalidValuePolicy::Fail => {
+                            return Err(ParseError::new(format!(
+                                "Invalid value \"{}\" for {}",
+                                value, name
+                            )))
+                        }
+                    }
+                }
             }
         }
 
-        results
+        Ok(())
     }
 }
 
+// the number for checking it, along with the text that is sent as it was
+fn to_number<'a>(value: &'a str, strip_suffixes: &[String]) -> Option<(f64, &'a str)> {
+    let mut value = value.trim();
+    if let Some(stripped) = strip_suffixes
+        .iter()
+        .find_map(|suffix| value.strip_suffix(suffix.as_str()))
+    {
+        value = stripped.trim_end();
+    }
+
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite())
+        .map(|number| (number, value))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -50,12 +172,14 @@ mod test {
             &[Output {
                 name: "name".to_string(),
                 value: "$1".to_string(),
+                ..Default::default()
             }],
-        );
+        )
+        .unwrap();
 
         assert_eq!(
-            parser.parse("input"),
-            [("prefix.name".to_string(), "input".to_string())]
+            parser.parse("1.5").unwrap().values,
+            [Metric::new("prefix.name", 1.5, 0)]
         );
     }
 
@@ -68,19 +192,22 @@ mod test {
                 Output {
                     name: "left".to_string(),
                     value: "$1".to_string(),
+                    ..Default::default()
                 },
                 Output {
                     name: "right".to_string(),
                     value: "$2".to_string(),
+                    ..Default::default()
                 },
             ],
-        );
+        )
+        .unwrap();
 
         assert_eq!(
-            parser.parse("1;2"),
+            parser.parse("1;2").unwrap().values,
             [
-                ("prefix.left".to_string(), "1".to_string()),
-                ("prefix.right".to_string(), "2".to_string())
+                Metric::new("prefix.left", 1.0, 0),
+                Metric::new("prefix.right", 2.0, 0)
             ]
         );
     }
@@ -93,14 +220,16 @@ mod test {
             &[Output {
                 name: "line".to_string(),
                 value: "$1".to_string(),
+                ..Default::default()
             }],
-        );
+        )
+        .unwrap();
 
         assert_eq!(
-            parser.parse("line1\nline2"),
+            parser.parse("1\n2").unwrap().values,
             [
-                ("prefix.line".to_string(), "line1".to_string()),
-                ("prefix.line".to_string(), "line2".to_string())
+                Metric::new("prefix.line", 1.0, 0),
+                Metric::new("prefix.line", 2.0, 0)
             ]
         );
     }
@@ -114,21 +243,206 @@ mod test {
                 Output {
                     name: "$1.left".to_string(),
                     value: "$2".to_string(),
+                    ..Default::default()
                 },
                 Output {
                     name: "$1.right".to_string(),
                     value: "$3".to_string(),
+                    ..Default::default()
                 },
             ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            parser.parse("line1 1;2\nline2 4;3").unwrap().values,
+            [
+                Metric::new("prefix.line1.left", 1.0, 0),
+                Metric::new("prefix.line1.right", 2.0, 0),
+                Metric::new("prefix.line2.left", 4.0, 0),
+                Metric::new("prefix.line2.right", 3.0, 0)
+            ]
         );
+    }
+
+    #[test]
+    fn test_named_groups_with_filters() {
+        let parser = Parser::new(
+            "prefix",
+            "^(?P<mount>\\S+)\\s+(?P<used>\\d+)%$",
+            &[Output {
+                name: "disk.${mount|sanitize}.used".to_string(),
+                value: "${used}".to_string(),
+                ..Default::default()
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(
+            parser.parse("/ 10%\n/mnt/Data.Backup 20%").unwrap().values,
+            [
+                Metric::new("prefix.disk._.used", 10.0, 0),
+                Metric::new("prefix.disk._mnt_Data_Backup.used", 20.0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strip_suffixes() {
+        let parser = Parser::new(
+            "prefix",
+            "^(\\w+) (.*)$",
+            &[Output {
+                name: "$1".to_string(),
+                value: "$2".to_string(),
+                strip_suffixes: vec!["%".to_string(), "kB".to_string()],
+                ..Default::default()
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(
+            parser.parse("cpu 12%\nmem 2048 kB\nload -0.5").unwrap(),
+            Parsed {
+                values: vec![
+                    Metric::new("prefix.cpu", 12.0, 0),
+                    Metric::new("prefix.mem", 2048.0, 0),
+                    Metric::new("prefix.load", -0.5, 0)
+                ],
+                invalid: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let outputs = |on_invalid| {
+            [Output {
+                name: "$1".to_string(),
+                value: "$2".to_string(),
+                on_invalid,
+                ..Default::default()
+            }]
+        };
+        let input = "a 1\nb N/A\nc 12ms\nd NaN";
+
+        let parser = Parser::new(
+            "prefix",
+            "^(\\w+) (.*)$",
+            &outputs(InvalidValuePolicy::Drop),
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(input).unwrap(),
+            Parsed {
+                values: vec![Metric::new("prefix.a", 1.0, 0)],
+                invalid: 3,
+            }
+        );
+
+        let parser = Parser::new(
+            "prefix",
+            "^(\\w+) (.*)$",
+            &outputs(InvalidValuePolicy::Zero),
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(input).unwrap(),
+            Parsed {
+                values: vec![
+                    Metric::new("prefix.a", 1.0, 0),
+                    Metric::new("prefix.b", 0.0, 0),
+                    Metric::new("prefix.c", 0.0, 0),
+                    Metric::new("prefix.d", 0.0, 0)
+                ],
+                invalid: 3,
+            }
+        );
+
+        let parser = Parser::new(
+            "prefix",
+            "^(\\w+) (.*)$",
+            &outputs(InvalidValuePolicy::Fail),
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(input).unwrap_err(),
+            ParseError::new("Invalid value \"N/A\" for prefix.b")
+        );
+    }
+
+    #[test]
+    fn test_type_and_tags() {
+        let parser = Parser::new(
+            "prefix",
+            "^(?P<mount>\\S+) (?P<used>\\d+)$",
+            &[Output {
+                name: "disk.used".to_string(),
+                value: "${used}".to_string(),
+                metric_type: MetricType::Counter,
+                tags: [("mount".to_string(), "${mount}".to_string())].into(),
+                ..Default::default()
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(
+            parser.parse("/mnt 10").unwrap().values,
+            [Metric {
+                metric_type: MetricType::Counter,
+                tags: vec![("mount".to_string(), "/mnt".to_string())],
+                ..Metric::new("prefix.disk.used", 10.0, 0)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_large_counters() {
+        let parser = Parser::new(
+            "prefix",
+            "^(.*)$",
+            &[Output {
+                name: "bytes".to_string(),
+                value: "$1".to_string(),
+                ..Default::default()
+            }],
+        )
+        .unwrap();
+
+        let values = parser.parse("18446744073709551615").unwrap().values;
+        assert_eq!("prefix.bytes 18446744073709551615 0", values[0].to_string());
+    }
+
+    #[test]
+    fn test_parse_matches() {
+        let parser = Parser::new(
+            "prefix",
+            "^(\\w+) (.*)$",
+            &[Output {
+                name: "$1".to_string(),
+                value: "$2".to_string(),
+                ..Default::default()
+            }],
+        )
+        .unwrap();
 
         assert_eq!(
-            parser.parse("line1 1;2\nline2 4;3"),
+            parser.parse_matches("a 1\nb N/A"),
             [
-                ("prefix.line1.left".to_string(), "1".to_string()),
-                ("prefix.line1.right".to_string(), "2".to_string()),
-                ("prefix.line2.left".to_string(), "4".to_string()),
-                ("prefix.line2.right".to_string(), "3".to_string())
+                (
+                    "a 1",
+                    Ok(Parsed {
+                        values: vec![Metric::new("prefix.a", 1.0, 0)],
+                        invalid: 0,
+                    })
+                ),
+                (
+                    "b N/A",
+                    Ok(Parsed {
+                        values: vec![],
+                        invalid: 1,
+                    })
+                )
             ]
         );
     }
@@ -0,0 +1,94 @@
+use crate::metric::Metric;
+
+const PROTO: u8 = 0x80;
+const EMPTY_LIST: u8 = b']';
+const MARK: u8 = b'(';
+const APPENDS: u8 = b'e';
+const STOP: u8 = b'.';
+const BINUNICODE: u8 = b'X';
+const BININT: u8 = b'J';
+const LONG1: u8 = 0x8a;
+const BINFLOAT: u8 = b'G';
+const TUPLE2: u8 = 0x86;
+
+fn push_int(buf: &mut Vec<u8>, value: u64) {
+    if let Ok(value) = i32::try_from(value) {
+        buf.push(BININT);
+        buf.extend_from_slice(&value.to_le_bytes());
+        return;
+    }
+
+    // little endian two's complement, as short as possible while staying positive
+    let mut bytes = value.to_le_bytes().to_vec();
+    while bytes.len() > 1 && bytes[bytes.len() - 1] == 0 && bytes[bytes.len() - 2] & 0x80 == 0 {
+        bytes.pop();
+    }
+    if bytes[bytes.len() - 1] & 0x80 != 0 {
+        bytes.push(0);
+    }
+    buf.push(LONG1);
+    buf.push(bytes.len() as u8);
+    buf.extend_from_slice(&bytes);
+}
+
+// a list of (path, (timestamp, value)) tuples in pickle protocol 2, prefixed with its length as
+// carbon's pickle receiver expects
+pub fn encode(metrics: &[Metric]) -> Vec<u8> {
+    let mut body = vec![PROTO, 2, EMPTY_LIST];
+    if !metrics.is_empty() {
+        body.push(MARK);
+        for metric in metrics {
+            body.push(BINUNICODE);
+            body.extend_from_slice(&(metric.name.len() as u32).to_le_bytes());
+            body.extend_from_slice(metric.name.as_bytes());
+            push_int(&mut body, metric.timestamp);
+            body.push(BINFLOAT);
+            body.extend_from_slice(&metric.value.to_be_bytes());
+            body.push(TUPLE2);
+            body.push(TUPLE2);
+        }
+        body.push(APPENDS);
+    }
+    body.push(STOP);
+
+    let mut payload = (body.len() as u32).to_be_bytes().to_vec();
+    payload.extend_from_slice(&body);
+    payload
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(b"\x00\x00\x00\x04\x80\x02].".to_vec(), encode(&[]));
+
+        let payload = encode(&[
+            Metric::new("a", 1.5, 1),
+            Metric::new("bc", -2.0, 1_650_000_000),
+        ]);
+        assert_eq!(
+            [
+                &b"\x00\x00\x00\x33"[..],
+                b"\x80\x02](",
+                b"X\x01\x00\x00\x00aJ\x01\x00\x00\x00G\x3f\xf8\x00\x00\x00\x00\x00\x00\x86\x86",
+                b"X\x02\x00\x00\x00bcJ\x80\x00\x59\x62G\xc0\x00\x00\x00\x00\x00\x00\x00\x86\x86",
+                b"e.",
+            ]
+            .concat(),
+            payload
+        );
+    }
+
+    #[test]
+    fn test_encode_large_timestamp() {
+        let mut buf = vec![];
+        push_int(&mut buf, 1 << 31);
+        assert_eq!(b"\x8a\x05\x00\x00\x00\x80\x00".to_vec(), buf);
+
+        let mut buf = vec![];
+        push_int(&mut buf, 1 << 32);
+        assert_eq!(b"\x8a\x05\x00\x00\x00\x00\x01".to_vec(), buf);
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::MetricType;
+use crate::metric::{Metric, MetricReporter, MetricReporterError};
+
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the latest series of every job, replaced as a whole on each run so that series a job stops
+// producing disappear with it, and dropped when a run produces nothing or the job is removed
+struct Exposition {
+    series: Mutex<HashMap<String, Vec<Metric>>>,
+}
+
+pub struct PrometheusReporter {
+    exposition: Arc<Exposition>,
+}
+
+impl MetricReporter for PrometheusReporter {
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError> {
+        let mut batches: HashMap<&str, Vec<Metric>> = HashMap::new();
+        for metric in metrics {
+            batches
+                .entry(metric.job.as_str())
+                .or_default()
+                .push(metric.clone());
+        }
+
+        let mut series = self.exposition.series.lock().unwrap();
+        for (job, metrics) in batches {
+            series.insert(job.to_owned(), metrics);
+        }
+        Ok(())
+    }
+
+    fn forget(&self, job: &str) {
+        self.exposition.series.lock().unwrap().remove(job);
+    }
+}
+
+// the hostname is left out like it is for opentsdb, so that every host exposes the same names
+fn metric_name(metric: &Metric) -> String {
+    let name = match (metric.prefix.as_str(), metric.path.is_empty()) {
+        (_, true) => metric.name.clone(),
+        ("", false) => metric.path.clone(),
+        (prefix, false) => format!("{}_{}", prefix, metric.path),
+    };
+    let name: String = name
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+
+    match name.starts_with(|c: char| c.is_ascii_digit()) {
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+fn label_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '_' {
+            true => c,
+            false => '_',
+        })
+        .collect();
+
+    match name.starts_with(|c: char| c.is_ascii_digit()) {
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Exposition {
+    fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut jobs: Vec<_> = series.keys().collect();
+        jobs.sort();
+
+        // series of the same name have to be grouped under a single TYPE line, and a sample can
+        // only be there once, so a repeated one replaces the earlier value
+        let mut names: Vec<String> = vec![];
+        let mut groups: HashMap<String, (MetricType, Vec<(String, String)>)> = HashMap::new();
+        for metric in jobs.into_iter().flat_map(|job| &series[job]) {
+            let name = metric_name(metric);
+            let labels: Vec<_> = metric
+                .tags
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", label_name(key), label_value(value)))
+                .collect();
+            let sample = match labels.is_empty() {
+                true => name.clone(),
+                false => format!("{}{{{}}}", name, labels.join(",")),
+            };
+
+            if !groups.contains_key(&name) {
+                names.push(name.clone());
+            }
+            let samples = &mut groups
+                .entry(name)
+                .or_insert_with(|| (metric.metric_type, vec![]))
+                .1;
+            match samples.iter_mut().find(|(existing, _)| *existing == sample) {
+                Some(existing) => existing.1 = metric.raw.clone(),
+                None => samples.push((sample, metric.raw.clone())),
+            }
+        }
+
+        let mut output = String::new();
+        for name in names {
+            let (metric_type, samples) = &groups[&name];
+            let metric_type = match metric_type {
+                MetricType::Counter => "counter",
+                MetricType::Gauge | MetricType::Timer => "gauge",
+            };
+            output.push_str(&format!("# TYPE {} {}\n", name, metric_type));
+            for (sample, value) in samples {
+                output.push_str(&format!("{} {}\n", sample, value));
+            }
+        }
+        output
+    }
+}
+
+fn respond(stream: net::TcpStream, exposition: &Exposition) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the rest of the request is of no interest, but has to be read before closing
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = match request_line.split(' ').nth(1) {
+        Some("/metrics") => ("200 OK", exposition.render()),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+// serves until the last reporter using the exposition is gone, which frees the address again
+fn serve(listener: net::TcpListener, exposition: Weak<Exposition>) {
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let exposition = match exposition.upgrade() {
+                    Some(exposition) => exposition,
+                    None => return,
+                };
+                let _ = stream.set_nonblocking(false);
+                if let Err(e) = respond(stream, &exposition) {
+                    log::warn!("Unable to serve metrics: {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if exposition.strong_count() == 0 {
+                    return;
+                }
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+            Err(e) => log::warn!("Unable to accept connection: {}", e),
+        }
+    }
+}
+
+// reporters for the same address share the exposition, so that a reload can replace them without
+// having to wait for the address to be freed
+pub fn build(address: &str) -> PrometheusReporter {
+    static EXPOSITIONS: OnceLock<Mutex<HashMap<String, Weak<Exposition>>>> = OnceLock::new();
+    let mut expositions = EXPOSITIONS.get_or_init(Default::default).lock().unwrap();
+
+    if let Some(exposition) = expositions.get(address).and_then(Weak::upgrade) {
+        return PrometheusReporter { exposition };
+    }
+
+    let exposition = Arc::new(Exposition {
+        series: Mutex::new(HashMap::new()),
+    });
+    expositions.insert(address.to_owned(), Arc::downgrade(&exposition));
+
+    let listener = net::TcpListener::bind(address).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    });
+    match listener {
+        Ok(listener) => {
+            log::info!("Serving metrics on {}", address);
+            let weak = Arc::downgrade(&exposition);
+            thread::Builder::new()
+                .name(format!("prometheus {}", address))
+                .spawn(move || serve(listener, weak))
+                .expect("Couldn't spawn thread");
+        }
+        Err(e) => log::error!("Unable to serve metrics on {}: {}", address, e),
+    }
+
+    PrometheusReporter { exposition }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::metric;
+    use std::io::Read;
+
+    // the registry is keyed on the address, so every test needs one of its own
+    fn free_address() -> String {
+        net::TcpListener::bind("localhost:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(
+            "regite_disk_used",
+            metric_name(&metric("job", "disk.used", 1.0, &[]))
+        );
+        assert_eq!(
+            "_1_load_avg",
+            metric_name(&Metric {
+                prefix: "1".to_string(),
+                ..metric("job", "load-avg", 1.0, &[])
+            })
+        );
+        assert_eq!(
+            "load_avg",
+            metric_name(&Metric {
+                prefix: String::new(),
+                ..metric("job", "load.avg", 1.0, &[])
+            })
+        );
+        assert_eq!("mount_point", label_name("mount.point"));
+        assert_eq!("a\\\"b\\\\c\\n", label_value("a\"b\\c\n"));
+    }
+
+    #[test]
+    fn test_staleness() {
+        let reporter = build(&free_address());
+        reporter
+            .report_batch(&[
+                metric("disk", "disk.used", 10.0, &[("mount", "/")]),
+                metric("disk", "disk.used", 20.0, &[("mount", "/mnt")]),
+                metric("load", "load", 0.5, &[]),
+            ])
+            .unwrap();
+        assert_eq!(
+            "# TYPE regite_disk_used gauge\n\
+             regite_disk_used{mount=\"/\"} 10\n\
+             regite_disk_used{mount=\"/mnt\"} 20\n\
+             # TYPE regite_load gauge\n\
+             regite_load 0.5\n",
+            reporter.exposition.render()
+        );
+
+        reporter
+            .report_batch(&[metric("disk", "disk.used", 15.0, &[("mount", "/")])])
+            .unwrap();
+        assert_eq!(
+            "# TYPE regite_disk_used gauge\n\
+             regite_disk_used{mount=\"/\"} 15\n\
+             # TYPE regite_load gauge\n\
+             regite_load 0.5\n",
+            reporter.exposition.render()
+        );
+
+        // a run that failed or found nothing takes the job's series away
+        reporter.forget("load");
+        assert_eq!(
+            "# TYPE regite_disk_used gauge\n\
+             regite_disk_used{mount=\"/\"} 15\n",
+            reporter.exposition.render()
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let reporter = build(&free_address());
+        reporter
+            .report_batch(&[
+                metric("first", "disk.used", 10.0, &[("mount", "/")]),
+                metric("first", "disk.used", 20.0, &[("mount", "/")]),
+                metric("second", "disk.used", 30.0, &[("mount", "/")]),
+            ])
+            .unwrap();
+        assert_eq!(
+            "# TYPE regite_disk_used gauge\n\
+             regite_disk_used{mount=\"/\"} 30\n",
+            reporter.exposition.render()
+        );
+    }
+
+    #[test]
+    fn test_serve() {
+        let address = free_address();
+        let reporter = build(&address);
+        reporter
+            .report_batch(&[Metric {
+                metric_type: MetricType::Counter,
+                ..metric("job", "requests", 3.0, &[])
+            }])
+            .unwrap();
+
+        // a second reporter for the same address shares the first one's series, and jobs with a
+        // prefix of their own keep it
+        let shared = build(&address);
+        assert!(Arc::ptr_eq(&reporter.exposition, &shared.exposition));
+        shared
+            .report_batch(&[Metric {
+                prefix: "apps".to_string(),
+                ..metric("other", "requests", 1.0, &[])
+            }])
+            .unwrap();
+
+        let get = |path: &str| {
+            let mut stream = net::TcpStream::connect(&address).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(
+            "\r\n\r\n# TYPE regite_requests counter\nregite_requests 3\n\
+             # TYPE apps_requests gauge\napps_requests 1\n"
+        ));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
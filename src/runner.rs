@@ -1,6 +1,8 @@
 use std::sync::mpsc::{channel, Sender};
 use std::thread::{self, JoinHandle};
-use std::time::{Duration, Instant};
+use std::time::{Instant, SystemTime};
+
+use crate::schedule::{Schedule, Time};
 
 struct RunningState {
     join_handle: Option<JoinHandle<()>>,
@@ -8,7 +10,11 @@ struct RunningState {
 }
 
 enum RunnerState {
-    Pending(String, Duration, Box<dyn Fn() + Send + 'static>),
+    Pending(
+        String,
+        Box<dyn Schedule>,
+        Box<dyn Fn(SystemTime) + Send + 'static>,
+    ),
     Running(RunningState),
     Stopped,
 }
@@ -18,31 +24,40 @@ pub struct Runner {
 }
 
 impl Runner {
-    pub fn new(name: String, interval: Duration, run_fn: Box<dyn Fn() + Send + 'static>) -> Runner {
+    pub fn new(
+        name: String,
+        schedule: Box<dyn Schedule>,
+        run_fn: Box<dyn Fn(SystemTime) + Send + 'static>,
+    ) -> Runner {
         Runner {
-            state: Some(RunnerState::Pending(name, interval, run_fn)),
+            state: Some(RunnerState::Pending(name, schedule, run_fn)),
         }
     }
 
     pub fn start(&mut self) {
-        let (name, interval, run_fn) = match std::mem::take(&mut self.state) {
-            Some(RunnerState::Pending(name, interval, run_fn)) => (name, interval, run_fn),
+        let (name, mut schedule, run_fn) = match std::mem::take(&mut self.state) {
+            Some(RunnerState::Pending(name, schedule, run_fn)) => (name, schedule, run_fn),
             _ => panic!("A runner can only be started once"),
         };
 
         let (tx, rx) = channel();
         let join_handle = thread::Builder::new()
             .name(name)
-            .spawn(move || loop {
-                let start_time = Instant::now();
-                run_fn();
-
-                let mut elapsed = start_time.elapsed();
-                while elapsed > interval {
-                    elapsed -= interval;
-                }
-                if rx.recv_timeout(interval - elapsed).is_ok() {
-                    break;
+            .spawn(move || {
+                let mut previous = None;
+                loop {
+                    let next = schedule.next(previous, Time::now());
+                    let start_at = next.instant + schedule.delay();
+                    if rx
+                        .recv_timeout(start_at.saturating_duration_since(Instant::now()))
+                        .is_ok()
+                    {
+                        break;
+                    }
+
+                    // the next run is timed from when this one really started
+                    previous = Some(Time::now());
+                    run_fn(next.system);
                 }
             })
             .expect("Couldn't spawn thread");
@@ -78,14 +93,16 @@ impl Runner {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::schedule::Interval;
+    use std::time::{Duration, Instant};
 
     #[test]
     #[should_panic(expected = "A runner can only be started once")]
     fn test_cannot_start_twice() {
         let mut runner = Runner::new(
             "name".to_string(),
-            Duration::from_millis(1),
-            Box::new(|| {}),
+            Box::new(Interval::new(Duration::from_millis(1))),
+            Box::new(|_| {}),
         );
 
         runner.start();
@@ -97,8 +114,8 @@ mod test {
     fn test_cannot_stop_before_start() {
         let runner = Runner::new(
             "name".to_string(),
-            Duration::from_millis(1),
-            Box::new(|| {}),
+            Box::new(Interval::new(Duration::from_millis(1))),
+            Box::new(|_| {}),
         );
 
         runner.stop();
@@ -109,8 +126,8 @@ mod test {
     fn test_cannot_join_before_start() {
         let mut runner = Runner::new(
             "name".to_string(),
-            Duration::from_millis(1),
-            Box::new(|| {}),
+            Box::new(Interval::new(Duration::from_millis(1))),
+            Box::new(|_| {}),
         );
 
         runner.join();
@@ -121,8 +138,8 @@ mod test {
     fn test_cannot_join_twice() {
         let mut runner = Runner::new(
             "name".to_string(),
-            Duration::from_millis(1),
-            Box::new(|| {}),
+            Box::new(Interval::new(Duration::from_millis(1))),
+            Box::new(|_| {}),
         );
 
         runner.start();
@@ -135,8 +152,8 @@ mod test {
     fn test_state_should_not_be_none() {
         let mut runner = Runner::new(
             "name".to_string(),
-            Duration::from_millis(1),
-            Box::new(|| {}),
+            Box::new(Interval::new(Duration::from_millis(1))),
+            Box::new(|_| {}),
         );
 
         assert!(runner.state.is_some());
@@ -147,4 +164,19 @@ mod test {
         runner.join();
         assert!(runner.state.is_some());
     }
+
+    #[test]
+    fn test_stops_while_waiting() {
+        let mut runner = Runner::new(
+            "name".to_string(),
+            Box::new(Interval::new(Duration::from_secs(3600))),
+            Box::new(|_| {}),
+        );
+
+        let start_time = Instant::now();
+        runner.start();
+        runner.stop();
+        runner.join();
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+    }
 }
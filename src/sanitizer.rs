@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::config::SanitizePolicy;
+use crate::metric::Metric;
+
+#[derive(Debug, PartialEq)]
+pub struct SanitizeError {
+    msg: String,
+}
+
+impl SanitizeError {
+    fn new<S: Into<String>>(msg: S) -> SanitizeError {
+        SanitizeError { msg: msg.into() }
+    }
+}
+
+impl std::fmt::Display for SanitizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for SanitizeError {}
+
+fn is_valid_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+// graphite takes most characters in a path, but whitespace splits the line, slashes end up as
+// directories of the whisper files and glob characters can't be queried
+fn is_valid_path_char(c: char) -> bool {
+    !(c.is_whitespace() || c.is_control() || "/\\*?[]{}".contains(c))
+}
+
+pub fn sanitize_component(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match is_valid_char(c) {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+// replaces invalid characters within each component and drops empty components, so stray dots
+// don't add levels to the hierarchy
+pub fn sanitize_path(path: &str) -> String {
+    path.split('.')
+        .filter(|component| !component.is_empty())
+        .map(|component| {
+            component
+                .chars()
+                .map(|c| match is_valid_path_char(c) {
+                    true => c,
+                    false => '_',
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+pub struct Sanitizer {
+    job: String,
+    policy: SanitizePolicy,
+    // the same invalid paths tend to come back on every run, so each is only warned about once
+    warned: Mutex<HashSet<String>>,
+}
+
+impl Sanitizer {
+    pub fn new(job: &str, policy: SanitizePolicy) -> Sanitizer {
+        Sanitizer {
+            job: job.to_owned(),
+            policy,
+            warned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn first_time(&self, name: &str) -> bool {
+        let mut warned = self.warned.lock().unwrap();
+        match warned.contains(name) {
+            true => false,
+            false => warned.insert(name.to_owned()),
+        }
+    }
+
+    pub fn sanitize(&self, metrics: Vec<Metric>) -> Result<Vec<Metric>, SanitizeError> {
+        let mut results = Vec::with_capacity(metrics.len());
+
+        for mut metric in metrics {
+            let sanitized = sanitize_path(&metric.name);
+            if sanitized == metric.name {
+                results.push(metric);
+                continue;
+            }
+
+            match self.policy {
+                SanitizePolicy::Replace => {
+                    if self.first_time(&metric.name) {
+                        log::warn!(
+                            "Job {} produced invalid metric path \"{}\", sending it as \"{}\"",
+                            self.job,
+                            metric.name,
+                            sanitized
+                        );
+                    }
+                    metric.name = sanitized;
+                    results.push(metric);
+                }
+                SanitizePolicy::Drop => {
+                    if self.first_time(&metric.name) {
+                        log::warn!(
+                            "Job {} produced invalid metric path \"{}\", dropping it",
+                            self.job,
+                            metric.name
+                        );
+                    }
+                }
+                // the error is logged along with the failed run
+                SanitizePolicy::Fail => {
+                    return Err(SanitizeError::new(format!(
+                        "Invalid metric path \"{}\"",
+                        metric.name
+                    )));
+                }
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metrics() -> Vec<Metric> {
+        vec![
+            Metric::new("prefix.valid-name_1", 1.0, 123),
+            Metric::new("prefix.disk./mnt/data.used", 2.0, 123),
+            Metric::new("prefix.with space.", 3.0, 123),
+        ]
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!("a.b_c.d", sanitize_path("a..b c.d."));
+        assert_eq!("a._mnt_data", sanitize_path("a./mnt/data"));
+        assert_eq!("a.b", sanitize_path("a.b"));
+        assert_eq!("a.b__", sanitize_path("a.b[*"));
+        // valid graphite paths stay as they are
+        assert_eq!(
+            "cpu.user:percent.sda1@home,x=1",
+            sanitize_path("cpu.user:percent.sda1@home,x=1")
+        );
+    }
+
+    #[test]
+    fn test_warned() {
+        let sanitizer = Sanitizer::new("job", SanitizePolicy::Replace);
+        assert!(sanitizer.first_time("a b"));
+        assert!(!sanitizer.first_time("a b"));
+        assert!(sanitizer.first_time("c d"));
+    }
+
+    #[test]
+    fn test_replace() {
+        let sanitizer = Sanitizer::new("job", SanitizePolicy::Replace);
+        assert_eq!(
+            Ok(vec![
+                Metric::new("prefix.valid-name_1", 1.0, 123),
+                Metric::new("prefix.disk._mnt_data.used", 2.0, 123),
+                Metric::new("prefix.with_space", 3.0, 123),
+            ]),
+            sanitizer.sanitize(metrics())
+        );
+    }
+
+    #[test]
+    fn test_drop() {
+        let sanitizer = Sanitizer::new("job", SanitizePolicy::Drop);
+        assert_eq!(
+            Ok(vec![Metric::new("prefix.valid-name_1", 1.0, 123)]),
+            sanitizer.sanitize(metrics())
+        );
+    }
+
+    #[test]
+    fn test_fail() {
+        let sanitizer = Sanitizer::new("job", SanitizePolicy::Fail);
+        assert_eq!(
+            SanitizeError::new("Invalid metric path \"prefix.disk./mnt/data.used\""),
+            sanitizer.sanitize(metrics()).unwrap_err()
+        );
+    }
+}
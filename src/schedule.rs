@@ -0,0 +1,705 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+
+use crate::config::{General, Job, ScheduleTimezone};
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// long enough to find the next 29th of February, even across a skipped leap year
+const MAX_SEARCH_YEARS: i32 = 8;
+
+#[derive(Debug, PartialEq)]
+pub struct ScheduleError {
+    msg: String,
+}
+
+impl ScheduleError {
+    fn new<S: Into<String>>(msg: S) -> ScheduleError {
+        ScheduleError { msg: msg.into() }
+    }
+}
+
+impl std::fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+// a point in time on both clocks: the monotonic one to wait for it, and the wall clock for what it
+// stands for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Time {
+    pub instant: Instant,
+    pub system: SystemTime,
+}
+
+impl Time {
+    pub fn now() -> Time {
+        Time {
+            instant: Instant::now(),
+            system: SystemTime::now(),
+        }
+    }
+
+    // the wall clock time as seen from now, which is when it's waited for
+    fn at(now: Time, system: SystemTime) -> Time {
+        Time {
+            instant: now.instant + system.duration_since(now.system).unwrap_or_default(),
+            system,
+        }
+    }
+
+    fn before(self, duration: Duration) -> Time {
+        Time {
+            instant: self.instant.checked_sub(duration).unwrap_or(self.instant),
+            system: self.system - duration,
+        }
+    }
+}
+
+pub trait Schedule: Send {
+    // previous is when the last run actually started
+    fn next(&mut self, previous: Option<Time>, now: Time) -> Time;
+
+    // how long after the scheduled time the upcoming run should actually start
+    fn delay(&mut self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+pub struct Interval {
+    period: Duration,
+    offset: Option<Duration>,
+}
+
+impl Interval {
+    pub fn new(period: Duration) -> Interval {
+        Interval {
+            period,
+            offset: None,
+        }
+    }
+
+    // fires on multiples of the period since the epoch, shifted by the offset
+    pub fn aligned(period: Duration, offset: Duration) -> Interval {
+        Interval {
+            period,
+            offset: Some(offset),
+        }
+    }
+
+    fn next_aligned(
+        &self,
+        offset: Duration,
+        previous: Option<SystemTime>,
+        now: SystemTime,
+    ) -> SystemTime {
+        let period = self.period.as_nanos();
+        let offset = offset.as_nanos() % period;
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_nanos();
+
+        let mut slot = (now.saturating_sub(offset) / period) * period + offset;
+        // the first run can fire right on the current slot, later ones always move forward
+        if slot < now || (slot == now && previous.is_some()) {
+            slot += period;
+        }
+        UNIX_EPOCH + Duration::from_nanos(slot as u64)
+    }
+}
+
+impl Schedule for Interval {
+    fn next(&mut self, previous: Option<Time>, now: Time) -> Time {
+        if let Some(offset) = self.offset {
+            if !self.period.is_zero() {
+                // slots are on the wall clock, but never go back to one that already ran
+                let previous = previous.map(|previous| previous.system);
+                let wall = match previous {
+                    Some(previous) if previous > now.system => previous,
+                    _ => now.system,
+                };
+                return Time::at(now, self.next_aligned(offset, previous, wall));
+            }
+        }
+
+        let previous = match previous {
+            Some(previous) if !self.period.is_zero() => previous,
+            _ => return now,
+        };
+
+        // plain intervals follow the monotonic clock, so changes to the wall clock don't affect
+        // them. runs that were missed while the previous one was still going are skipped
+        let elapsed = now.instant.saturating_duration_since(previous.instant);
+        let periods = elapsed.as_nanos() / self.period.as_nanos() + 1;
+        let next =
+            previous.instant + Duration::from_nanos((self.period.as_nanos() * periods) as u64);
+        Time {
+            instant: next,
+            system: now.system + (next - now.instant),
+        }
+    }
+}
+
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+    timezone: ScheduleTimezone,
+}
+
+impl Cron {
+    pub fn parse(expr: &str, timezone: ScheduleTimezone) -> Result<Cron, ScheduleError> {
+        let expanded = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expr => expr,
+        };
+
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(ScheduleError::new(format!(
+                "Expected 5 fields in schedule \"{}\"",
+                expr
+            )));
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7, &WEEKDAY_NAMES, 0)?;
+        // both 0 and 7 mean sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        let cron = Cron {
+            minutes: parse_field(fields[0], 0, 59, &[], 0)?,
+            hours: parse_field(fields[1], 0, 23, &[], 0)?,
+            days: parse_field(fields[2], 1, 31, &[], 0)?,
+            months: parse_field(fields[3], 1, 12, &MONTH_NAMES, 1)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+            timezone,
+        };
+
+        let start = NaiveDate::from_ymd(2000, 1, 1).and_hms(0, 0, 0);
+        match cron.next_after(start) {
+            Some(_) => Ok(cron),
+            None => Err(ScheduleError::new(format!(
+                "Schedule \"{}\" never fires",
+                expr
+            ))),
+        }
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        // like cron, when both are restricted a match on either is enough
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time =
+            after.date().and_hms(after.hour(), after.minute(), 0) + chrono::Duration::minutes(1);
+        let max_year = time.year() + MAX_SEARCH_YEARS;
+
+        while time.year() <= max_year {
+            if self.months & (1 << time.month()) == 0 {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = NaiveDate::from_ymd(year, month, 1).and_hms(0, 0, 0);
+            } else if !self.matches_day(time.date()) {
+                time = time.date().succ().and_hms(0, 0, 0);
+            } else if self.hours & (1 << time.hour()) == 0 {
+                time = time.date().and_hms(time.hour(), 0, 0) + chrono::Duration::hours(1);
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                time += chrono::Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+
+        None
+    }
+
+    fn naive_time(&self, time: SystemTime) -> NaiveDateTime {
+        match self.timezone {
+            ScheduleTimezone::Local => DateTime::<Local>::from(time).naive_local(),
+            ScheduleTimezone::Utc => DateTime::<Utc>::from(time).naive_utc(),
+        }
+    }
+
+    fn system_time(&self, time: NaiveDateTime) -> Option<SystemTime> {
+        // local times skipped by a DST change don't exist, and repeated ones fire the first time
+        match self.timezone {
+            ScheduleTimezone::Local => Local.from_local_datetime(&time).earliest().map(Into::into),
+            ScheduleTimezone::Utc => Some(Utc.from_utc_datetime(&time).into()),
+        }
+    }
+}
+
+impl Schedule for Cron {
+    fn next(&mut self, _previous: Option<Time>, now: Time) -> Time {
+        let mut after = self.naive_time(now.system);
+        while let Some(candidate) = self.next_after(after) {
+            match self.system_time(candidate) {
+                Some(time) if time > now.system => return Time::at(now, time),
+                _ => after = candidate,
+            }
+        }
+
+        unreachable!("Schedules are checked to fire when parsed")
+    }
+}
+
+pub struct Splayed {
+    schedule: Box<dyn Schedule>,
+    max_millis: u64,
+    key: String,
+    every_run: bool,
+    runs: u64,
+    // the splay of the last run, which shifts the whole schedule so it keeps its phase
+    current: Duration,
+}
+
+impl Splayed {
+    // the splay is derived from the key rather than picked at random, so it survives restarts
+    pub fn new(schedule: Box<dyn Schedule>, max: Duration, every_run: bool, key: &str) -> Splayed {
+        Splayed {
+            schedule,
+            max_millis: max.as_millis() as u64,
+            key: key.to_string(),
+            every_run,
+            runs: 0,
+            current: Duration::ZERO,
+        }
+    }
+
+    fn splay(&self) -> Duration {
+        let hash = match self.every_run {
+            true => fnv1a(format!("{}.{}", self.key, self.runs).as_bytes()),
+            false => fnv1a(self.key.as_bytes()),
+        };
+        match self.max_millis {
+            0 => Duration::ZERO,
+            max_millis => Duration::from_millis(hash % max_millis),
+        }
+    }
+}
+
+impl Schedule for Splayed {
+    // the wrapped schedule sees the times without the splay, so the next run is counted from
+    // where the last one would have started
+    fn next(&mut self, previous: Option<Time>, now: Time) -> Time {
+        match previous {
+            Some(previous) => self.schedule.next(
+                Some(previous.before(self.current)),
+                now.before(self.current),
+            ),
+            None => self.schedule.next(None, now),
+        }
+    }
+
+    // every run is shifted by the same splay, or by a different one each time with every_run
+    fn delay(&mut self) -> Duration {
+        self.current = self.splay();
+        self.runs += 1;
+        self.current
+    }
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn parse_value(value: &str, names: &[&str], offset: u32) -> Result<u32, ScheduleError> {
+    if let Some(i) = names
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(value))
+    {
+        return Ok(i as u32 + offset);
+    }
+
+    value
+        .parse()
+        .map_err(|_| ScheduleError::new(format!("Invalid schedule value \"{}\"", value)))
+}
+
+fn parse_field(
+    field: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+    offset: u32,
+) -> Result<u64, ScheduleError> {
+    let mut bits = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => {
+                    return Err(ScheduleError::new(format!(
+                        "Invalid schedule step \"{}\"",
+                        part
+                    )))
+                }
+            },
+            None => (part, None),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_value(start, names, offset)?,
+                parse_value(end, names, offset)?,
+            )
+        } else {
+            let start = parse_value(range, names, offset)?;
+            (start, if step.is_some() { max } else { start })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(ScheduleError::new(format!(
+                "Schedule range \"{}\" is outside of {}-{}",
+                part, min, max
+            )));
+        }
+
+        for value in (start..=end).step_by(step.unwrap_or(1)) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+pub fn build(job: &Job, general: &General) -> Result<Box<dyn Schedule>, ScheduleError> {
+    let schedule: Box<dyn Schedule> = match &job.schedule {
+        Some(schedule) => Box::new(Cron::parse(schedule, job.timezone)?),
+        None if job.interval == 0 => {
+            return Err(ScheduleError::new(
+                "Interval must be greater than 0, or a schedule set",
+            ))
+        }
+        None if job.align => Box::new(Interval::aligned(
+            Duration::from_secs(job.interval),
+            Duration::from_secs(job.align_offset),
+        )),
+        None => Box::new(Interval::new(Duration::from_secs(job.interval))),
+    };
+
+    let splay = job.splay.unwrap_or(general.splay);
+    if splay == 0 {
+        return Ok(schedule);
+    }
+
+    Ok(Box::new(Splayed::new(
+        schedule,
+        Duration::from_secs(splay),
+        job.splay_every_run.unwrap_or(general.splay_every_run),
+        &format!("{}.{}", general.hostname(), job.name),
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> SystemTime {
+        Utc.ymd(year, month, day).and_hms(hour, minute, 0).into()
+    }
+
+    // a fixed instant for every wall clock time, so both clocks agree in the tests
+    fn time(system: SystemTime) -> Time {
+        thread_local! {
+            static BASE: Instant = Instant::now();
+        }
+        let since = system.duration_since(utc(2000, 1, 1, 0, 0)).unwrap();
+        Time {
+            instant: BASE.with(|base| *base + since),
+            system,
+        }
+    }
+
+    fn next(expr: &str, now: SystemTime) -> SystemTime {
+        Cron::parse(expr, ScheduleTimezone::Utc)
+            .unwrap()
+            .next(None, time(now))
+            .system
+    }
+
+    #[test]
+    fn test_interval() {
+        let start = time(utc(2022, 1, 1, 0, 0));
+        let mut interval = Interval::new(Duration::from_secs(60));
+
+        assert_eq!(start, interval.next(None, start));
+        assert_eq!(
+            start.instant + Duration::from_secs(60),
+            interval
+                .next(Some(start), time(start.system + Duration::from_secs(10)))
+                .instant
+        );
+        assert_eq!(
+            start.instant + Duration::from_secs(180),
+            interval
+                .next(Some(start), time(start.system + Duration::from_secs(150)))
+                .instant
+        );
+    }
+
+    #[test]
+    fn test_interval_ignores_wall_clock() {
+        let start = time(utc(2022, 1, 1, 0, 0));
+        let mut interval = Interval::new(Duration::from_secs(60));
+
+        // the wall clock went back an hour while the job ran
+        let now = Time {
+            instant: start.instant + Duration::from_secs(10),
+            system: utc(2021, 12, 31, 23, 0) + Duration::from_secs(10),
+        };
+        let next = interval.next(Some(start), now);
+        assert_eq!(start.instant + Duration::from_secs(60), next.instant);
+        assert_eq!(utc(2021, 12, 31, 23, 1), next.system);
+    }
+
+    #[test]
+    fn test_aligned_interval() {
+        let next = |interval: &mut Interval, previous: Option<SystemTime>, now: SystemTime| {
+            interval.next(previous.map(time), time(now)).system
+        };
+
+        let mut interval = Interval::aligned(Duration::from_secs(60), Duration::from_secs(0));
+        assert_eq!(
+            utc(2022, 1, 1, 0, 1),
+            next(
+                &mut interval,
+                None,
+                utc(2022, 1, 1, 0, 0) + Duration::from_secs(17)
+            )
+        );
+        assert_eq!(
+            utc(2022, 1, 1, 0, 1),
+            next(&mut interval, None, utc(2022, 1, 1, 0, 1))
+        );
+        assert_eq!(
+            utc(2022, 1, 1, 0, 2),
+            next(
+                &mut interval,
+                Some(utc(2022, 1, 1, 0, 1)),
+                utc(2022, 1, 1, 0, 1)
+            )
+        );
+        assert_eq!(
+            utc(2022, 1, 1, 0, 4),
+            next(
+                &mut interval,
+                Some(utc(2022, 1, 1, 0, 1)),
+                utc(2022, 1, 1, 0, 3) + Duration::from_secs(1)
+            )
+        );
+
+        let mut interval = Interval::aligned(Duration::from_secs(60), Duration::from_secs(15));
+        assert_eq!(
+            utc(2022, 1, 1, 0, 0) + Duration::from_secs(15),
+            next(&mut interval, None, utc(2022, 1, 1, 0, 0))
+        );
+        assert_eq!(
+            utc(2022, 1, 1, 0, 1) + Duration::from_secs(15),
+            next(
+                &mut interval,
+                None,
+                utc(2022, 1, 1, 0, 0) + Duration::from_secs(16)
+            )
+        );
+    }
+
+    #[test]
+    fn test_build_zero_interval() {
+        let job = Job {
+            name: "job".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            ScheduleError::new("Interval must be greater than 0, or a schedule set"),
+            build(&job, &General::default()).err().unwrap()
+        );
+    }
+
+    // runs the schedule like the runner does, with every run starting on time and taking 5 seconds
+    fn run_times(schedule: &mut dyn Schedule, start: SystemTime, runs: usize) -> Vec<SystemTime> {
+        let mut previous = None;
+        let mut now = time(start);
+        let mut times = vec![];
+        for _ in 0..runs {
+            let next = schedule.next(previous, now);
+            let started = time(next.system + schedule.delay());
+            times.push(started.system);
+            previous = Some(started);
+            now = time(started.system + Duration::from_secs(5));
+        }
+        times
+    }
+
+    fn splayed(schedule: Interval, every_run: bool, key: &str) -> Splayed {
+        Splayed::new(Box::new(schedule), Duration::from_secs(30), every_run, key)
+    }
+
+    #[test]
+    fn test_splay() {
+        let start = utc(2022, 1, 1, 0, 0);
+        let interval = || Interval::new(Duration::from_secs(60));
+
+        let times = run_times(&mut splayed(interval(), false, "host.job"), start, 3);
+        let splay = times[0].duration_since(start).unwrap();
+        assert_ne!(Duration::ZERO, splay);
+        assert!(splay < Duration::from_secs(30));
+        // the splay shifts every later run too
+        assert_eq!(start + Duration::from_secs(60) + splay, times[1]);
+        assert_eq!(start + Duration::from_secs(120) + splay, times[2]);
+
+        assert_eq!(
+            times,
+            run_times(&mut splayed(interval(), false, "host.job"), start, 3)
+        );
+        assert_ne!(
+            times,
+            run_times(&mut splayed(interval(), false, "otherhost.job"), start, 3)
+        );
+    }
+
+    #[test]
+    fn test_splay_aligned() {
+        let start = utc(2022, 1, 1, 0, 0) + Duration::from_secs(10);
+        let mut schedule = splayed(
+            Interval::aligned(Duration::from_secs(60), Duration::ZERO),
+            false,
+            "host.job",
+        );
+
+        let times = run_times(&mut schedule, start, 3);
+        let splay = times[0].duration_since(utc(2022, 1, 1, 0, 1)).unwrap();
+        assert_eq!(utc(2022, 1, 1, 0, 2) + splay, times[1]);
+        assert_eq!(utc(2022, 1, 1, 0, 3) + splay, times[2]);
+    }
+
+    #[test]
+    fn test_splay_every_run() {
+        let start = utc(2022, 1, 1, 0, 0);
+        let mut schedule = splayed(Interval::new(Duration::from_secs(60)), true, "host.job");
+
+        let times = run_times(&mut schedule, start, 3);
+        let splays: Vec<_> = times
+            .iter()
+            .enumerate()
+            .map(|(i, time)| {
+                time.duration_since(start + Duration::from_secs(60 * i as u64))
+                    .unwrap()
+            })
+            .collect();
+        assert!(splays.iter().all(|splay| *splay < Duration::from_secs(30)));
+        assert_ne!(splays[0], splays[1]);
+        assert_ne!(splays[1], splays[2]);
+    }
+
+    #[test]
+    fn test_cron_steps() {
+        let now = utc(2022, 3, 1, 10, 3) + Duration::from_secs(30);
+        assert_eq!(utc(2022, 3, 1, 10, 5), next("*/5 * * * *", now));
+        assert_eq!(utc(2022, 3, 1, 10, 4), next("* * * * *", now));
+        assert_eq!(utc(2022, 3, 1, 10, 10), next("10-20/10 * * * *", now));
+    }
+
+    #[test]
+    fn test_cron_daily() {
+        assert_eq!(
+            utc(2022, 3, 2, 2, 0),
+            next("0 2 * * *", utc(2022, 3, 1, 2, 0))
+        );
+        assert_eq!(
+            utc(2022, 3, 2, 0, 0),
+            next("@daily", utc(2022, 3, 1, 12, 0))
+        );
+    }
+
+    #[test]
+    fn test_cron_days() {
+        // 2022-03-01 is a tuesday
+        assert_eq!(
+            utc(2022, 3, 4, 0, 0),
+            next("0 0 * * fri", utc(2022, 3, 1, 0, 0))
+        );
+        assert_eq!(
+            utc(2022, 3, 6, 0, 0),
+            next("0 0 * * 7", utc(2022, 3, 1, 0, 0))
+        );
+        assert_eq!(
+            utc(2022, 3, 4, 0, 0),
+            next("0 0 15 * 5", utc(2022, 3, 1, 0, 0))
+        );
+        assert_eq!(
+            utc(2022, 4, 1, 0, 0),
+            next("0 0 1 Apr-Jun *", utc(2022, 3, 1, 0, 0))
+        );
+        assert_eq!(
+            utc(2024, 2, 29, 0, 0),
+            next("0 0 29 2 *", utc(2022, 3, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_cron_invalid() {
+        assert_eq!(
+            ScheduleError::new("Expected 5 fields in schedule \"* * *\""),
+            Cron::parse("* * *", ScheduleTimezone::Utc).err().unwrap()
+        );
+        assert_eq!(
+            ScheduleError::new("Schedule range \"60\" is outside of 0-59"),
+            Cron::parse("60 * * * *", ScheduleTimezone::Utc)
+                .err()
+                .unwrap()
+        );
+        assert_eq!(
+            ScheduleError::new("Invalid schedule step \"*/0\""),
+            Cron::parse("*/0 * * * *", ScheduleTimezone::Utc)
+                .err()
+                .unwrap()
+        );
+        assert_eq!(
+            ScheduleError::new("Invalid schedule value \"x\""),
+            Cron::parse("x * * * *", ScheduleTimezone::Utc)
+                .err()
+                .unwrap()
+        );
+        assert_eq!(
+            ScheduleError::new("Schedule \"0 0 31 2 *\" never fires"),
+            Cron::parse("0 0 31 2 *", ScheduleTimezone::Utc)
+                .err()
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::config::{self, FileFormat, MetricType};
+use crate::metric::{Metric, MetricReporter, MetricReporterError};
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_line(metric: &Metric) -> String {
+    let metric_type = match metric.metric_type {
+        MetricType::Gauge => "gauge",
+        MetricType::Counter => "counter",
+        MetricType::Timer => "timer",
+    };
+    let tags: Vec<_> = metric
+        .tags
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect();
+
+    format!(
+        "{{\"name\":{},\"value\":{},\"timestamp\":{},\"job\":{},\"type\":\"{}\",\"tags\":{{{}}}}}",
+        json_string(&metric.name),
+        metric.value,
+        metric.timestamp,
+        json_string(&metric.job),
+        metric_type,
+        tags.join(",")
+    )
+}
+
+fn format(metrics: &[Metric], format: FileFormat) -> String {
+    let mut output = String::new();
+    for metric in metrics {
+        match format {
+            FileFormat::Plaintext => output.push_str(&metric.to_string()),
+            FileFormat::Json => output.push_str(&json_line(metric)),
+        }
+        output.push('\n');
+    }
+    output
+}
+
+pub struct StdoutReporter {
+    format: FileFormat,
+}
+
+impl MetricReporter for StdoutReporter {
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError> {
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(format(metrics, self.format).as_bytes())
+            .and_then(|()| stdout.flush())
+            .map_err(|e| MetricReporterError::new(format!("IO Error: {}", e)))
+    }
+}
+
+struct OpenFile {
+    file: File,
+    size: u64,
+    id: Option<(u64, u64)>,
+}
+
+// the device and inode, which tell whether the path still leads to the open file
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+pub struct FileReporter {
+    path: PathBuf,
+    format: FileFormat,
+    max_size: u64,
+    max_backups: u32,
+    file: Mutex<Option<OpenFile>>,
+}
+
+impl FileReporter {
+    fn backup_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    // shifts file.1 to file.2 and so on, dropping the oldest, and moves the current file to file.1
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_backups == 0 {
+            return fs::remove_file(&self.path);
+        }
+
+        for index in (1..self.max_backups).rev() {
+            let from = self.backup_path(index);
+            if from.exists() {
+                fs::rename(&from, self.backup_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.backup_path(1))
+    }
+
+    fn open(&self) -> io::Result<OpenFile> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let metadata = file.metadata()?;
+        Ok(OpenFile {
+            file,
+            size: metadata.len(),
+            id: file_id(&metadata),
+        })
+    }
+
+    // false once something else, like logrotate, moved or deleted the file
+    fn is_current(&self, open: &OpenFile) -> bool {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => open.id.is_none() || file_id(&metadata) == open.id,
+            Err(_) => false,
+        }
+    }
+
+    fn write(&self, contents: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        if file.as_ref().is_some_and(|open| !self.is_current(open)) {
+            *file = None;
+        }
+        if file.is_none() {
+            *file = Some(self.open()?);
+        }
+
+        // a max size of zero disables rotation. a file left full by an earlier run is rotated too
+        let size = file.as_ref().map_or(0, |open| open.size);
+        if self.max_size > 0 && size > 0 && size + contents.len() as u64 > self.max_size {
+            *file = None;
+            self.rotate()?;
+            *file = Some(self.open()?);
+        }
+
+        let open = file.as_mut().unwrap();
+        open.file.write_all(contents.as_bytes())?;
+        open.size += contents.len() as u64;
+        Ok(())
+    }
+}
+
+impl MetricReporter for FileReporter {
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError> {
+        self.write(&format(metrics, self.format)).map_err(|e| {
+            MetricReporterError::new(format!("Unable to write {}: {}", self.path.display(), e))
+        })
+    }
+}
+
+pub fn build_stdout(config: &config::General) -> StdoutReporter {
+    StdoutReporter {
+        format: config.file_format,
+    }
+}
+
+pub fn build_file(path: &str, config: &config::General) -> FileReporter {
+    FileReporter {
+        path: PathBuf::from(path),
+        format: config.file_format,
+        max_size: config.file_max_size,
+        max_backups: config.file_max_backups,
+        file: Mutex::new(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metric() -> Metric {
+        Metric {
+            job: "job".to_string(),
+            metric_type: MetricType::Counter,
+            tags: vec![("mount".to_string(), "/mnt/\"data\"".to_string())],
+            ..Metric::new("prefix.name", 1.5, 123)
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            "prefix.name 1.5 123\n",
+            format(&[metric()], FileFormat::Plaintext)
+        );
+        assert_eq!(
+            "{\"name\":\"prefix.name\",\"value\":1.5,\"timestamp\":123,\"job\":\"job\",\
+             \"type\":\"counter\",\"tags\":{\"mount\":\"/mnt/\\\"data\\\"\"}}\n",
+            format(&[metric()], FileFormat::Json)
+        );
+        assert_eq!("\"a\\u0001\\n\"", json_string("a\u{1}\n"));
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("regite-sink-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn reporter(path: &std::path::Path) -> FileReporter {
+        FileReporter {
+            path: path.to_path_buf(),
+            format: FileFormat::Plaintext,
+            max_size: 40,
+            max_backups: 2,
+            file: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = temp_dir("rotation");
+        let path = dir.join("metrics.log");
+        let reporter = reporter(&path);
+
+        // every batch is 18 bytes, so each file fits two of them
+        for i in 0..7 {
+            reporter
+                .report_batch(&[Metric::new("prefix.name", i as f64, 123)])
+                .unwrap();
+        }
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!("prefix.name 6 123\n", read(path.clone()));
+        assert_eq!(
+            "prefix.name 4 123\nprefix.name 5 123\n",
+            read(reporter.backup_path(1))
+        );
+        assert_eq!(
+            "prefix.name 2 123\nprefix.name 3 123\n",
+            read(reporter.backup_path(2))
+        );
+        assert!(!reporter.backup_path(3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotates_full_file_on_open() {
+        let dir = temp_dir("full");
+        let path = dir.join("metrics.log");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "x".repeat(30)).unwrap();
+
+        reporter(&path)
+            .report_batch(&[Metric::new("prefix.name", 1.0, 123)])
+            .unwrap();
+        assert_eq!("prefix.name 1 123\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "x".repeat(30),
+            fs::read_to_string(dir.join("metrics.log.1")).unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_reopens_moved_file() {
+        let dir = temp_dir("moved");
+        let path = dir.join("metrics.log");
+        let reporter = reporter(&path);
+
+        reporter
+            .report_batch(&[Metric::new("prefix.name", 1.0, 123)])
+            .unwrap();
+        fs::rename(&path, dir.join("moved.log")).unwrap();
+        reporter
+            .report_batch(&[Metric::new("prefix.name", 2.0, 123)])
+            .unwrap();
+        assert_eq!("prefix.name 2 123\n", fs::read_to_string(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+        reporter
+            .report_batch(&[Metric::new("prefix.name", 3.0, 123)])
+            .unwrap();
+        assert_eq!("prefix.name 3 123\n", fs::read_to_string(&path).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
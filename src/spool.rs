@@ -0,0 +1,358 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::metric::{Metric, MetricReporter, MetricReporterError};
+use crate::sanitizer::sanitize_component;
+use crate::schedule::fnv1a;
+
+const REPLAY_BATCH_SIZE: usize = 1000;
+
+struct Spool {
+    path: PathBuf,
+    max_size: u64,
+    max_age: u64,
+}
+
+impl Spool {
+    // reads the spooled metrics in order, or only the first few of them
+    fn read(&self, limit: Option<usize>) -> io::Result<Vec<Metric>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        let mut metrics = vec![];
+        for line in BufReader::new(file).lines() {
+            if limit.is_some_and(|limit| metrics.len() >= limit) {
+                break;
+            }
+            if let Some(metric) = parse_line(&line?) {
+                metrics.push(metric);
+            }
+        }
+        Ok(metrics)
+    }
+
+    // only ever appends, so an outage doesn't rewrite the spool on every run. once it is full
+    // the new metrics are the ones discarded
+    fn append(&self, metrics: &[Metric]) -> io::Result<()> {
+        let lines: String = metrics.iter().map(format_line).collect();
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        if size + lines.len() as u64 > self.max_size {
+            return Err(io::Error::other(format!(
+                "Spool is full, discarding {} metrics",
+                metrics.len()
+            )));
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(lines.as_bytes())
+    }
+
+    fn write(&self, metrics: &[Metric]) -> io::Result<()> {
+        let metrics = self.trim(metrics);
+        if metrics.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let lines: String = metrics.iter().map(format_line).collect();
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, lines)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    // drops anything older than the max age, then the oldest lines until it fits the max size
+    fn trim<'a>(&self, metrics: &'a [Metric]) -> &'a [Metric] {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let mut start = metrics
+            .iter()
+            .position(|metric| metric.timestamp + self.max_age >= now)
+            .unwrap_or(metrics.len());
+
+        let mut size: u64 = metrics[start..]
+            .iter()
+            .map(|metric| format_line(metric).len() as u64)
+            .sum();
+        while size > self.max_size {
+            size -= format_line(&metrics[start]).len() as u64;
+            start += 1;
+        }
+
+        if start > 0 {
+            log::warn!("Discarded {} spooled metrics over the spool limits", start);
+        }
+        &metrics[start..]
+    }
+}
+
+// one json object per line, so everything a metric carries survives the outage
+fn format_line(metric: &Metric) -> String {
+    let mut line = serde_json::to_string(metric).expect("Unable to serialize metric");
+    line.push('\n');
+    line
+}
+
+// also takes the name value timestamp lines of spools written before they were json
+fn parse_line(line: &str) -> Option<Metric> {
+    if line.starts_with('{') {
+        return serde_json::from_str(line).ok();
+    }
+
+    let mut parts = line.rsplitn(3, ' ');
+    let timestamp = parts.next()?.parse().ok()?;
+    let raw = parts.next()?;
+    let name = parts.next()?;
+    Some(Metric {
+        raw: raw.to_owned(),
+        ..Metric::new(name, raw.parse().ok()?, timestamp)
+    })
+}
+
+struct SpoolingReporter {
+    reporter: Arc<dyn MetricReporter>,
+    spool: Mutex<Spool>,
+}
+
+impl SpoolingReporter {
+    fn replay(&self, spool: &Spool) -> Result<(), MetricReporterError> {
+        // the first chunk shows whether the destination is back, without reading the whole
+        // spool on every run of an outage
+        let first = match spool.read(Some(REPLAY_BATCH_SIZE)) {
+            Ok(first) => first,
+            Err(e) => {
+                log::error!("Unable to read spool {}: {}", spool.path.display(), e);
+                return Ok(());
+            }
+        };
+        if first.is_empty() {
+            return Ok(());
+        }
+        self.reporter.report_batch(spool.trim(&first))?;
+
+        let spooled = match spool.read(None) {
+            Ok(spooled) => spooled,
+            Err(e) => {
+                log::error!("Unable to read spool {}: {}", spool.path.display(), e);
+                return Ok(());
+            }
+        };
+        let rest = spool.trim(spooled.get(first.len()..).unwrap_or_default());
+        for (i, chunk) in rest.chunks(REPLAY_BATCH_SIZE).enumerate() {
+            if let Err(e) = self.reporter.report_batch(chunk) {
+                if let Err(e) = spool.write(&rest[i * REPLAY_BATCH_SIZE..]) {
+                    log::error!("Unable to write spool {}: {}", spool.path.display(), e);
+                }
+                return Err(e);
+            }
+        }
+
+        log::info!("Replayed {} spooled metrics", first.len() + rest.len());
+        if let Err(e) = spool.write(&[]) {
+            log::error!("Unable to clear spool {}: {}", spool.path.display(), e);
+        }
+        Ok(())
+    }
+}
+
+impl MetricReporter for SpoolingReporter {
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError> {
+        let spool = self.spool.lock().unwrap();
+        let result = self
+            .replay(&spool)
+            .and_then(|()| self.reporter.report_batch(metrics));
+
+        if result.is_err() && !metrics.is_empty() {
+            match spool.append(metrics) {
+                Ok(()) => log::warn!(
+                    "Spooled {} metrics to {}",
+                    metrics.len(),
+                    spool.path.display()
+                ),
+                Err(e) => log::error!("Unable to write spool {}: {}", spool.path.display(), e),
+            }
+        }
+        result
+    }
+
+    fn forget(&self, job: &str) {
+        self.reporter.forget(job);
+    }
+}
+
+// the default destination keeps the original spool name, others get one named after them. the
+// sanitized address can be the same for different destinations, so a hash of the whole one is added
+fn spool_name(destination: &config::Destination, config: &config::General) -> String {
+    match *destination == config.destination() {
+        true => "graphite.spool".to_string(),
+        false => {
+            let connection_type = format!("{:?}", destination.connection_type).to_lowercase();
+            let hash = fnv1a(format!("{}:{}", connection_type, destination.address).as_bytes());
+            format!(
+                "graphite-{}-{}-{:016x}.spool",
+                connection_type,
+                sanitize_component(&destination.address),
+                hash
+            )
+        }
+    }
+}
+
+pub fn build(
+    reporter: Arc<dyn MetricReporter>,
+    destination: &config::Destination,
+    config: &config::General,
+) -> Arc<dyn MetricReporter> {
+    match &config.spool_dir {
+        Some(spool_dir) => Arc::new(SpoolingReporter {
+            reporter,
+            spool: Mutex::new(Spool {
+                path: PathBuf::from(spool_dir).join(spool_name(destination, config)),
+                max_size: config.spool_max_size,
+                max_age: config.spool_max_age,
+            }),
+        }),
+        None => reporter,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::FakeReporter;
+    use std::sync::atomic::Ordering;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn spool(name: &str, max_size: u64, max_age: u64) -> Spool {
+        let path = std::env::temp_dir()
+            .join(format!("regite-spool-test-{}", std::process::id()))
+            .join(format!("{}.spool", name));
+        let _ = fs::remove_file(&path);
+        Spool {
+            path,
+            max_size,
+            max_age,
+        }
+    }
+
+    #[test]
+    fn test_spool_name() {
+        let general = config::General {
+            graphite_address: "localhost:2003".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            "graphite.spool",
+            spool_name(&general.destination(), &general)
+        );
+        let udp = |address: &str| {
+            spool_name(
+                &config::Destination {
+                    connection_type: config::GraphiteConnectionType::Udp,
+                    address: address.to_string(),
+                },
+                &general,
+            )
+        };
+        assert_eq!(
+            "graphite-udp-apps_2003-653ea0f6ef67570b.spool",
+            udp("apps:2003")
+        );
+        assert_ne!(udp("apps:2003"), udp("apps_2003"));
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let metric = Metric {
+            metric_type: config::MetricType::Counter,
+            tags: vec![("mount".to_string(), "/mnt data".to_string())],
+            job: "disk".to_string(),
+            path: "disk.used".to_string(),
+            ..Metric::new("prefix.host.disk.used", 1.5, 123)
+        };
+        assert_eq!(
+            Some(metric.clone()),
+            parse_line(format_line(&metric).trim_end())
+        );
+        assert_eq!(
+            Some(Metric::new("name", 1.5, 123)),
+            parse_line("name 1.5 123")
+        );
+        assert_eq!(None, parse_line("garbage"));
+        assert_eq!(None, parse_line("{garbage"));
+    }
+
+    #[test]
+    fn test_replays_in_order() {
+        let fake = Arc::new(FakeReporter::new(false));
+        let reporter = SpoolingReporter {
+            reporter: fake.clone(),
+            spool: Mutex::new(spool("replay", 1024, 3600)),
+        };
+
+        let first = Metric::new("first", 1.0, now() - 2);
+        let second = Metric::new("second", 2.0, now() - 1);
+        let third = Metric::new("third", 3.0, now());
+        assert!(reporter.report_batch(std::slice::from_ref(&first)).is_err());
+        assert!(reporter
+            .report_batch(std::slice::from_ref(&second))
+            .is_err());
+
+        fake.available.store(true, Ordering::SeqCst);
+        assert!(reporter.report_batch(std::slice::from_ref(&third)).is_ok());
+        assert_eq!(vec![first, second, third], *fake.reported.lock().unwrap());
+        assert!(!reporter.spool.lock().unwrap().path.exists());
+    }
+
+    #[test]
+    fn test_limits() {
+        let old = Metric::new("old", 1.0, now() - 100);
+        let first = Metric::new("first", 1.0, now());
+        let second = Metric::new("second", 2.0, now());
+        let third = Metric::new("third", 3.0, now());
+
+        let max_size = (format_line(&first).len() + format_line(&second).len()) as u64;
+        let spool = spool("limits", max_size, 10);
+        spool.append(&[first.clone(), second.clone()]).unwrap();
+        assert!(spool.append(std::slice::from_ref(&third)).is_err());
+        assert_eq!(
+            vec![first.clone(), second.clone()],
+            spool.read(None).unwrap()
+        );
+        assert_eq!(vec![first.clone()], spool.read(Some(1)).unwrap());
+
+        spool.write(&[old, first.clone()]).unwrap();
+        assert_eq!(vec![first], spool.read(None).unwrap());
+        spool.write(&[]).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{General, Job};
+use crate::executor::{self, Executor, ExecutorErrorKind};
+use crate::metric::{Metric, MetricReporter};
+use crate::parser::{Parsed, Parser};
+use crate::sanitizer::{sanitize_path, Sanitizer};
+
+#[derive(Debug, PartialEq)]
+pub struct TaskError {
+    msg: String,
+}
+
+impl TaskError {
+    fn new<S: Into<String>>(msg: S) -> TaskError {
+        TaskError { msg: msg.into() }
+    }
+}
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for TaskError {}
+
+#[derive(Debug, PartialEq)]
+pub struct JobMatch {
+    pub text: String,
+    pub lines: Vec<String>,
+    pub problem: Option<String>,
+}
+
+pub struct Task {
+    name: String,
+    prefix: String,
+    base_prefix: String,
+    host: String,
+    command: String,
+    align: bool,
+    executor: Box<dyn Executor>,
+    parser: Parser,
+    sanitizer: Sanitizer,
+    tags: Vec<(String, String)>,
+}
+
+impl Task {
+    pub fn new(job: &Job, general: &General) -> Result<Task, TaskError> {
+        let prefix = job.prefix(general);
+        Ok(Task {
+            name: job.name.clone(),
+            // names are only split off the prefix once sanitized, so it has to be sanitized too
+            prefix: format!("{}.", sanitize_path(&prefix)),
+            base_prefix: sanitize_path(job.base_prefix(general)),
+            host: match job.omit_hostname {
+                true => String::new(),
+                false => general.hostname(),
+            },
+            command: job.command.clone(),
+            align: job.align,
+            executor: executor::build(job.timeout(general)),
+            parser: Parser::new(&prefix, &job.regex, &job.output)
+                .map_err(|e| TaskError::new(e.to_string()))?,
+            sanitizer: Sanitizer::new(&job.name, job.sanitize),
+            tags: job
+                .tags
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        })
+    }
+
+    pub fn run(
+        &self,
+        scheduled_time: SystemTime,
+        metrics: &dyn MetricReporter,
+    ) -> Result<(), TaskError> {
+        // aligned runs report the slot they belong to, so points land on its bucket
+        let start_time = match self.align {
+            true => scheduled_time,
+            false => SystemTime::now(),
+        };
+        let batch = self.execute().and_then(|output| {
+            let parsed = self
+                .parser
+                .parse(&output)
+                .map_err(|e| TaskError::new(e.to_string()))?;
+            // each value was warned about on its own, this is the total for the run
+            if parsed.invalid > 0 {
+                log::warn!(
+                    "Job {} produced {} invalid values",
+                    self.name,
+                    parsed.invalid
+                );
+            }
+            self.collect(parsed, start_time)
+        });
+
+        // whatever the last run produced no longer holds when this one failed or found nothing
+        match batch {
+            Ok(batch) if !batch.is_empty() => metrics
+                .report_batch(&batch)
+                .map_err(|e| TaskError::new(e.to_string())),
+            Ok(_) => {
+                metrics.forget(&self.name);
+                Ok(())
+            }
+            Err(e) => {
+                metrics.forget(&self.name);
+                Err(e)
+            }
+        }
+    }
+
+    // runs the output through everything but the reporter, reporting on each match separately
+    pub fn dry_run(&self, input: Option<&str>) -> Result<Vec<JobMatch>, TaskError> {
+        let output = match input {
+            Some(input) => input.to_owned(),
+            None => self.execute()?,
+        };
+
+        let mut matches = vec![];
+        for (text, parsed) in self.parser.parse_matches(&output) {
+            let batch = parsed
+                .map_err(|e| TaskError::new(e.to_string()))
+                .and_then(|parsed| {
+                    let invalid = parsed.invalid;
+                    self.collect(parsed, SystemTime::now())
+                        .map(|batch| (batch, invalid))
+                });
+
+            matches.push(match batch {
+                Ok((batch, invalid)) => JobMatch {
+                    text: text.to_owned(),
+                    lines: batch.iter().map(Metric::to_string).collect(),
+                    problem: match invalid {
+                        0 => None,
+                        invalid => Some(format!("{} invalid values", invalid)),
+                    },
+                },
+                Err(e) => JobMatch {
+                    text: text.to_owned(),
+                    lines: vec![],
+                    problem: Some(e.to_string()),
+                },
+            });
+        }
+
+        Ok(matches)
+    }
+
+    fn execute(&self) -> Result<String, TaskError> {
+        self.executor
+            .execute(&self.command)
+            .map_err(|e| match e.kind() {
+                ExecutorErrorKind::TimedOut => TaskError::new(format!("Command timed out: {}", e)),
+                ExecutorErrorKind::Failed => TaskError::new(e.to_string()),
+            })
+    }
+
+    fn collect(&self, parsed: Parsed, start_time: SystemTime) -> Result<Vec<Metric>, TaskError> {
+        let epoch_time = start_time
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let batch = parsed
+            .values
+            .into_iter()
+            .map(|mut metric| {
+                metric.timestamp = epoch_time;
+                metric.job = self.name.clone();
+                metric.prefix = self.base_prefix.clone();
+                metric.host = self.host.clone();
+                // tags of the output take precedence over the ones of the job
+                for (key, value) in &self.tags {
+                    if !metric.tags.iter().any(|(existing, _)| existing == key) {
+                        metric.tags.push((key.clone(), value.clone()));
+                    }
+                }
+                metric
+            })
+            .collect();
+
+        let mut batch = self
+            .sanitizer
+            .sanitize(batch)
+            .map_err(|e| TaskError::new(e.to_string()))?;
+        for metric in &mut batch {
+            metric.path = match metric.name.strip_prefix(&self.prefix) {
+                Some(path) => path.to_owned(),
+                None => metric.name.clone(),
+            };
+        }
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Output;
+
+    #[test]
+    fn test_dry_run() {
+        let task = Task::new(
+            &Job {
+                name: "job".to_string(),
+                regex: "^(\\S+) (\\S+)$".to_string(),
+                output: vec![Output {
+                    name: "$1".to_string(),
+                    value: "$2".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            &General {
+                prefix: "prefix".to_string(),
+                hostname: Some("host".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let matches = task.dry_run(Some("a 1\nb N/A\nc/d 2\nignored")).unwrap();
+        assert_eq!(3, matches.len());
+
+        assert_eq!("a 1", matches[0].text);
+        assert_eq!(1, matches[0].lines.len());
+        assert!(matches[0].lines[0].starts_with("prefix.host.a 1 "));
+        assert_eq!(None, matches[0].problem);
+
+        assert_eq!("b N/A", matches[1].text);
+        assert!(matches[1].lines.is_empty());
+        assert_eq!(Some("1 invalid values".to_string()), matches[1].problem);
+
+        assert!(matches[2].lines[0].starts_with("prefix.host.c_d 2 "));
+    }
+
+    #[test]
+    fn test_collect_tags() {
+        let task = Task::new(
+            &Job {
+                regex: "^(\\S+)$".to_string(),
+                output: vec![Output {
+                    name: "name".to_string(),
+                    value: "$1".to_string(),
+                    tags: [("env".to_string(), "test".to_string())].into(),
+                    ..Default::default()
+                }],
+                tags: [
+                    ("env".to_string(), "prod".to_string()),
+                    ("team".to_string(), "ops".to_string()),
+                ]
+                .into(),
+                ..Default::default()
+            },
+            &General::default(),
+        )
+        .unwrap();
+
+        let parsed = task.parser.parse("1").unwrap();
+        let batch = task.collect(parsed, UNIX_EPOCH).unwrap();
+        assert_eq!(
+            vec![
+                ("env".to_string(), "test".to_string()),
+                ("team".to_string(), "ops".to_string())
+            ],
+            batch[0].tags
+        );
+        assert_eq!(0, batch[0].timestamp);
+        assert_eq!("name", batch[0].path);
+    }
+
+    #[test]
+    fn test_collect_path() {
+        let job = Job {
+            regex: "^(\\S+) (\\S+)$".to_string(),
+            output: vec![Output {
+                name: "$1".to_string(),
+                value: "$2".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let general = General {
+            prefix: "prefix".to_string(),
+            hostname: Some("my host".to_string()),
+            ..Default::default()
+        };
+        let task = Task::new(&job, &general).unwrap();
+
+        let parsed = task.parser.parse("disk/used 1").unwrap();
+        let batch = task.collect(parsed, UNIX_EPOCH).unwrap();
+        assert_eq!("prefix.my_host.disk_used", batch[0].name);
+        assert_eq!("disk_used", batch[0].path);
+        assert_eq!("prefix", batch[0].prefix);
+        assert_eq!("my host", batch[0].host);
+
+        let task = Task::new(
+            &Job {
+                prefix: Some("apps".to_string()),
+                omit_hostname: true,
+                ..job
+            },
+            &general,
+        )
+        .unwrap();
+        let parsed = task.parser.parse("disk 1").unwrap();
+        let batch = task.collect(parsed, UNIX_EPOCH).unwrap();
+        assert_eq!("apps.disk", batch[0].name);
+        assert_eq!("disk", batch[0].path);
+        assert_eq!("apps", batch[0].prefix);
+        assert_eq!("", batch[0].host);
+    }
+}
@@ -0,0 +1,257 @@
+use regex::Captures;
+
+use crate::sanitizer::sanitize_component;
+
+#[derive(Debug, PartialEq)]
+pub struct TemplateError {
+    msg: String,
+}
+
+impl TemplateError {
+    fn new<S: Into<String>>(msg: S) -> TemplateError {
+        TemplateError { msg: msg.into() }
+    }
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Group {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Filter {
+    Lowercase,
+    ReplaceDots,
+    Trim,
+    Sanitize,
+}
+
+impl Filter {
+    fn parse(name: &str) -> Result<Filter, TemplateError> {
+        match name.trim() {
+            "lowercase" => Ok(Filter::Lowercase),
+            "replace-dots" => Ok(Filter::ReplaceDots),
+            "trim" => Ok(Filter::Trim),
+            "sanitize" => Ok(Filter::Sanitize),
+            name => Err(TemplateError::new(format!("Unknown filter \"{}\"", name))),
+        }
+    }
+
+    fn apply(&self, value: String) -> String {
+        match self {
+            Filter::Lowercase => value.to_lowercase(),
+            Filter::ReplaceDots => value.replace('.', "_"),
+            Filter::Trim => value.trim().to_string(),
+            Filter::Sanitize => sanitize_component(&value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Group(Group, Vec<Filter>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    // supports $1, $name, ${name} and ${name|filter|...}, with $$ for a literal dollar sign
+    pub fn parse(template: &str) -> Result<Template, TemplateError> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = template;
+
+        while let Some(i) = rest.find('$') {
+            literal.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            if let Some(stripped) = rest.strip_prefix('$') {
+                literal.push('$');
+                rest = stripped;
+                continue;
+            }
+
+            let part = if let Some(braced) = rest.strip_prefix('{') {
+                let end = match braced.find('}') {
+                    Some(end) => end,
+                    None => {
+                        return Err(TemplateError::new(format!(
+                            "Unclosed \"${{\" in \"{}\"",
+                            template
+                        )))
+                    }
+                };
+                rest = &braced[end + 1..];
+
+                let mut pieces = braced[..end].split('|');
+                let group = parse_group(pieces.next().unwrap_or("").trim(), template)?;
+                let filters = pieces.map(Filter::parse).collect::<Result<_, _>>()?;
+                Part::Group(group, filters)
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                // a dollar sign that isn't followed by a group is just a dollar sign
+                if end == 0 {
+                    literal.push('$');
+                    continue;
+                }
+                let group = parse_group(&rest[..end], template)?;
+                rest = &rest[end..];
+                Part::Group(group, vec![])
+            };
+
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(part);
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Template { parts })
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &Group> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Group(group, _) => Some(group),
+            Part::Literal(_) => None,
+        })
+    }
+
+    pub fn render(&self, captures: &Captures) -> String {
+        let mut result = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => result.push_str(literal),
+                Part::Group(group, filters) => {
+                    let value = match group {
+                        Group::Index(i) => captures.get(*i),
+                        Group::Name(name) => captures.name(name),
+                    }
+                    .map_or("", |mat| mat.as_str());
+
+                    result.push_str(
+                        &filters
+                            .iter()
+                            .fold(value.to_string(), |value, filter| filter.apply(value)),
+                    );
+                }
+            }
+        }
+        result
+    }
+}
+
+fn parse_group(group: &str, template: &str) -> Result<Group, TemplateError> {
+    if group.is_empty() {
+        return Err(TemplateError::new(format!(
+            "Missing capture group name in \"{}\"",
+            template
+        )));
+    }
+
+    Ok(match group.parse() {
+        Ok(i) => Group::Index(i),
+        Err(_) => Group::Name(group.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use regex::Regex;
+
+    fn render(regex: &str, template: &str, input: &str) -> String {
+        let captures = Regex::new(regex).unwrap().captures(input).unwrap();
+        Template::parse(template).unwrap().render(&captures)
+    }
+
+    #[test]
+    fn test_numbered_groups() {
+        assert_eq!("b.a", render("(\\w) (\\w)", "$2.$1", "a b"));
+        assert_eq!("bx", render("(\\w) (\\w)", "${2}x", "a b"));
+    }
+
+    #[test]
+    fn test_named_groups() {
+        assert_eq!(
+            "disk.sda1.used",
+            render(
+                "(?P<dev>\\w+) (?P<used>\\d+)",
+                "disk.${dev}.used",
+                "sda1 50"
+            )
+        );
+        assert_eq!("sda1_x", render("(?P<dev>\\w+)", "${dev}_x", "sda1"));
+        assert_eq!("", render("(?P<dev>\\w+)", "$missing", "sda1"));
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(
+            "mount._var_lib_docker",
+            render(
+                "(?P<mount>\\S+)",
+                "mount.${mount|sanitize}",
+                "/var/lib/docker"
+            )
+        );
+        assert_eq!(
+            "host_example_com",
+            render(
+                "(.*)",
+                "${1 | trim | lowercase | replace-dots}",
+                "  Host.Example.COM "
+            )
+        );
+    }
+
+    #[test]
+    fn test_literal_dollar() {
+        assert_eq!("$1", render("(.*)", "$$1", "value"));
+        assert_eq!("cost.$", render("(.*)", "cost.$", "value"));
+        assert_eq!("$-value", render("(.*)", "$-$1", "value"));
+    }
+
+    #[test]
+    fn test_groups() {
+        let template = Template::parse("${mount|sanitize}.$1").unwrap();
+        assert_eq!(
+            vec![&Group::Name("mount".to_string()), &Group::Index(1)],
+            template.groups().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            TemplateError::new("Unknown filter \"upper\""),
+            Template::parse("${1|upper}").unwrap_err()
+        );
+        assert_eq!(
+            TemplateError::new("Unclosed \"${\" in \"${1\""),
+            Template::parse("${1").unwrap_err()
+        );
+        assert_eq!(
+            TemplateError::new("Missing capture group name in \"a.${}\""),
+            Template::parse("a.${}").unwrap_err()
+        );
+    }
+}
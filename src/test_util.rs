@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::metric::{Metric, MetricReporter, MetricReporterError};
+
+// a metric of a job that omits the hostname, the way the task hands it to the reporters
+pub fn metric(job: &str, path: &str, value: f64, tags: &[(&str, &str)]) -> Metric {
+    Metric {
+        job: job.to_string(),
+        path: path.to_string(),
+        prefix: "regite".to_string(),
+        tags: tags
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        ..Metric::new(format!("regite.{}", path), value, 123)
+    }
+}
+
+// keeps whatever it's given while available, and fails otherwise
+pub struct FakeReporter {
+    pub available: AtomicBool,
+    pub reported: Mutex<Vec<Metric>>,
+}
+
+impl FakeReporter {
+    pub fn new(available: bool) -> FakeReporter {
+        FakeReporter {
+            available: AtomicBool::new(available),
+            reported: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl MetricReporter for FakeReporter {
+    fn report_batch(&self, metrics: &[Metric]) -> Result<(), MetricReporterError> {
+        match self.available.load(Ordering::SeqCst) {
+            true => {
+                self.reported.lock().unwrap().extend_from_slice(metrics);
+                Ok(())
+            }
+            false => Err(MetricReporterError::new("unavailable")),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::config::{self, Config, Job, Location};
+use crate::schedule::Cron;
+use crate::template::{Group, Template};
+
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub job: Option<String>,
+    pub location: Option<Location>,
+    pub msg: String,
+}
+
+impl Problem {
+    fn new<S: Into<String>>(job: &Job, msg: S) -> Problem {
+        Problem {
+            job: Some(job.name.clone()),
+            location: job.location.clone(),
+            msg: msg.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        if let Some(job) = &self.job {
+            write!(f, "job \"{}\": ", job)?;
+        }
+        write!(f, "{}", self.msg)
+    }
+}
+
+fn validate_job(job: &Job, problems: &mut Vec<Problem>) {
+    if job.name.is_empty() {
+        problems.push(Problem::new(job, "Name is empty"));
+    }
+
+    match &job.schedule {
+        Some(schedule) => {
+            if let Err(e) = Cron::parse(schedule, job.timezone) {
+                problems.push(Problem::new(job, e.to_string()));
+            }
+        }
+        None if job.interval == 0 => {
+            problems.push(Problem::new(
+                job,
+                "Interval must be greater than 0, or a schedule set",
+            ));
+        }
+        None => {}
+    }
+
+    match shlex::split(&job.command) {
+        Some(parts) if parts.is_empty() => problems.push(Problem::new(job, "Command is empty")),
+        Some(_) => {}
+        None => problems.push(Problem::new(
+            job,
+            format!("Unable to parse command \"{}\"", job.command),
+        )),
+    }
+
+    let regex = match Regex::new(&format!("(?m){}", job.regex)) {
+        Ok(regex) => Some(regex),
+        Err(e) => {
+            problems.push(Problem::new(job, format!("Invalid regex: {}", e)));
+            None
+        }
+    };
+
+    if job.output.is_empty() {
+        problems.push(Problem::new(job, "No outputs configured"));
+    }
+
+    for output in &job.output {
+        let templates = [&output.name, &output.value]
+            .into_iter()
+            .chain(output.tags.values());
+        for template in templates {
+            let parsed = match Template::parse(template) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    problems.push(Problem::new(job, format!("Invalid output: {}", e)));
+                    continue;
+                }
+            };
+
+            let regex = match &regex {
+                Some(regex) => regex,
+                None => continue,
+            };
+            for group in parsed.groups() {
+                let exists = match group {
+                    Group::Index(i) => *i < regex.captures_len(),
+                    Group::Name(name) => regex.capture_names().flatten().any(|n| n == name),
+                };
+                if !exists {
+                    let group = match group {
+                        Group::Index(i) => i.to_string(),
+                        Group::Name(name) => name.clone(),
+                    };
+                    problems.push(Problem::new(
+                        job,
+                        format!(
+                            "Output \"{}\" refers to capture group \"{}\", which the regex doesn't have",
+                            template, group
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+pub fn validate(config: &Config) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut names: HashMap<&str, &Job> = HashMap::new();
+
+    for job in &config.job {
+        validate_job(job, &mut problems);
+
+        if let Some(first) = names.insert(&job.name, job) {
+            problems.push(Problem::new(
+                job,
+                match &first.location {
+                    Some(location) => format!("Duplicate job name, first defined at {}", location),
+                    None => "Duplicate job name".to_string(),
+                },
+            ));
+        }
+    }
+
+    problems
+}
+
+pub fn validate_file(file_path: &str, config_dir: Option<&str>) -> Vec<Problem> {
+    match config::load_config(file_path, config_dir) {
+        Ok(config) => validate(&config),
+        Err(e) => vec![Problem {
+            job: None,
+            location: None,
+            msg: format!("Unable to load {}: {}", file_path, e),
+        }],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Output;
+
+    fn job(name: &str, line: usize) -> Job {
+        Job {
+            name: name.to_string(),
+            interval: 60,
+            command: "echo 1".to_string(),
+            regex: "(?P<value>\\d+)".to_string(),
+            output: vec![Output {
+                name: "name".to_string(),
+                value: "${value}".to_string(),
+                ..Default::default()
+            }],
+            location: Some(Location {
+                file: "regite.toml".to_string(),
+                line,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn messages(config: &Config) -> Vec<String> {
+        validate(config).iter().map(Problem::to_string).collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        let config = Config {
+            job: vec![job("a", 1), job("b", 10)],
+            ..Default::default()
+        };
+
+        assert!(validate(&config).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_job() {
+        let mut invalid = job("a", 5);
+        invalid.interval = 0;
+        invalid.command = "\"".to_string();
+        invalid.regex = "(".to_string();
+
+        let messages = messages(&Config {
+            job: vec![invalid],
+            ..Default::default()
+        });
+        assert_eq!(3, messages.len());
+        assert_eq!(
+            "regite.toml:5: job \"a\": Interval must be greater than 0, or a schedule set",
+            messages[0]
+        );
+        assert_eq!(
+            "regite.toml:5: job \"a\": Unable to parse command \"\"\"",
+            messages[1]
+        );
+        assert!(messages[2].starts_with("regite.toml:5: job \"a\": Invalid regex: "));
+    }
+
+    #[test]
+    fn test_missing_groups() {
+        let mut invalid = job("a", 5);
+        invalid.schedule = Some("* * *".to_string());
+        invalid.output[0].name = "$2.${value|upper}".to_string();
+        invalid.output[0].value = "${missing}".to_string();
+
+        assert_eq!(
+            vec![
+                "regite.toml:5: job \"a\": Expected 5 fields in schedule \"* * *\"",
+                "regite.toml:5: job \"a\": Invalid output: Unknown filter \"upper\"",
+                "regite.toml:5: job \"a\": Output \"${missing}\" refers to capture group \"missing\", which the regex doesn't have",
+            ],
+            messages(&Config {
+                job: vec![invalid],
+                ..Default::default()
+            })
+        );
+
+        let mut invalid = job("a", 5);
+        invalid.output[0].name = "$2".to_string();
+        assert_eq!(
+            vec![
+                "regite.toml:5: job \"a\": Output \"$2\" refers to capture group \"2\", which the regex doesn't have",
+            ],
+            messages(&Config {
+                job: vec![invalid],
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        assert_eq!(
+            vec!["regite.toml:10: job \"a\": Duplicate job name, first defined at regite.toml:1"],
+            messages(&Config {
+                job: vec![job("a", 1), job("a", 10)],
+                ..Default::default()
+            })
+        );
+    }
+}
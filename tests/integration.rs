@@ -1,19 +1,19 @@
 #![warn(clippy::all)]
 #![cfg(target_os = "linux")]
 
-use std::io::Read;
+use std::io::{BufRead, BufReader};
 use std::net;
 use std::sync::{
     atomic::{AtomicU32, Ordering},
-    Arc,
+    Arc, Mutex,
 };
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use regex::Regex;
 
 use regite::{
-    config::{Config, General, GraphiteConnectionType, Job, Output},
+    config::{Config, Destination, General, GraphiteConnectionType, Job, Output},
     Regite,
 };
 
@@ -32,20 +32,44 @@ fn create_listener() -> (Arc<AtomicU32>, String) {
     (counter, address)
 }
 
-fn create_tcp_listener() -> (Arc<AtomicU32>, String) {
+fn create_tcp_listener() -> (Arc<AtomicU32>, Arc<AtomicU32>, String) {
     let counter = Arc::new(AtomicU32::new(0));
+    let connections = Arc::new(AtomicU32::new(0));
     let counter_clone = counter.clone();
+    let connections_clone = connections.clone();
     let socket = net::TcpListener::bind("localhost:0").unwrap();
     let address = socket.local_addr().unwrap().to_string();
+    thread::spawn(move || loop {
+        let (stream, _) = socket.accept().unwrap();
+        connections_clone.fetch_add(1, Ordering::SeqCst);
+        let counter_clone = counter_clone.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stream).lines() {
+                line.unwrap();
+                counter_clone.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+    });
+    (counter, connections, address)
+}
+
+fn create_recording_listener() -> (Arc<Mutex<Vec<String>>>, String) {
+    let lines = Arc::new(Mutex::new(vec![]));
+    let lines_clone = lines.clone();
+    let socket = net::UdpSocket::bind("localhost:0").unwrap();
+    let address = socket.local_addr().unwrap().to_string();
     thread::spawn(move || {
-        let mut buf = [0; 100];
+        let mut buf = [0; 1500];
         loop {
-            let (mut stream, _) = socket.accept().unwrap();
-            let _ = stream.read(&mut buf).unwrap();
-            counter_clone.fetch_add(1, Ordering::SeqCst);
+            let len = socket.recv(&mut buf).unwrap();
+            let data = String::from_utf8_lossy(&buf[..len]).to_string();
+            lines_clone
+                .lock()
+                .unwrap()
+                .extend(data.lines().map(str::to_string));
         }
     });
-    (counter, address)
+    (lines, address)
 }
 
 #[test]
@@ -54,9 +78,10 @@ fn test_date() {
     let mut regite = Regite::new(Config {
         general: General {
             prefix: "prefix".to_string(),
-            hostname: "host".to_string(),
+            hostname: Some("host".to_string()),
             graphite_connection_type: GraphiteConnectionType::Udp,
             graphite_address: socket.local_addr().unwrap().to_string(),
+            ..Default::default()
         },
         job: vec![Job {
             name: "name".to_string(),
@@ -66,9 +91,13 @@ fn test_date() {
             output: vec![Output {
                 name: "date".to_string(),
                 value: "$1".to_string(),
+                ..Default::default()
             }],
+            ..Default::default()
         }],
-    });
+        ..Default::default()
+    })
+    .unwrap();
 
     let re = Regex::new("([^ ]+) (\\d+) (\\d+)").unwrap();
     let mut buf = [0; 100];
@@ -92,9 +121,10 @@ fn test_short_job() {
     let mut regite = Regite::new(Config {
         general: General {
             prefix: "prefix".to_string(),
-            hostname: "host".to_string(),
+            hostname: Some("host".to_string()),
             graphite_connection_type: GraphiteConnectionType::Udp,
             graphite_address: address,
+            ..Default::default()
         },
         job: vec![Job {
             name: "name".to_string(),
@@ -104,9 +134,13 @@ fn test_short_job() {
             output: vec![Output {
                 name: "name".to_string(),
                 value: "$1".to_string(),
+                ..Default::default()
             }],
+            ..Default::default()
         }],
-    });
+        ..Default::default()
+    })
+    .unwrap();
 
     regite.start();
     thread::sleep(Duration::from_secs(5));
@@ -118,13 +152,14 @@ fn test_short_job() {
 
 #[test]
 fn test_short_job_tcp() {
-    let (counter, address) = create_tcp_listener();
+    let (counter, connections, address) = create_tcp_listener();
     let mut regite = Regite::new(Config {
         general: General {
             prefix: "prefix".to_string(),
-            hostname: "host".to_string(),
+            hostname: Some("host".to_string()),
             graphite_connection_type: GraphiteConnectionType::Tcp,
             graphite_address: address,
+            ..Default::default()
         },
         job: vec![Job {
             name: "name".to_string(),
@@ -134,9 +169,13 @@ fn test_short_job_tcp() {
             output: vec![Output {
                 name: "name".to_string(),
                 value: "$1".to_string(),
+                ..Default::default()
             }],
+            ..Default::default()
         }],
-    });
+        ..Default::default()
+    })
+    .unwrap();
 
     regite.start();
     thread::sleep(Duration::from_secs(5));
@@ -144,6 +183,7 @@ fn test_short_job_tcp() {
     regite.join();
 
     assert_eq!(5, counter.load(Ordering::SeqCst));
+    assert_eq!(1, connections.load(Ordering::SeqCst));
 }
 
 #[test]
@@ -152,9 +192,10 @@ fn test_long_job() {
     let mut regite = Regite::new(Config {
         general: General {
             prefix: "prefix".to_string(),
-            hostname: "host".to_string(),
+            hostname: Some("host".to_string()),
             graphite_connection_type: GraphiteConnectionType::Udp,
             graphite_address: address,
+            ..Default::default()
         },
         job: vec![Job {
             name: "name".to_string(),
@@ -164,9 +205,13 @@ fn test_long_job() {
             output: vec![Output {
                 name: "name".to_string(),
                 value: "$1".to_string(),
+                ..Default::default()
             }],
+            ..Default::default()
         }],
-    });
+        ..Default::default()
+    })
+    .unwrap();
 
     regite.start();
     thread::sleep(Duration::from_secs(5));
@@ -186,13 +231,14 @@ fn test_long_job() {
 
 #[test]
 fn test_long_job_tcp() {
-    let (counter, address) = create_tcp_listener();
+    let (counter, connections, address) = create_tcp_listener();
     let mut regite = Regite::new(Config {
         general: General {
             prefix: "prefix".to_string(),
-            hostname: "host".to_string(),
+            hostname: Some("host".to_string()),
             graphite_connection_type: GraphiteConnectionType::Tcp,
             graphite_address: address,
+            ..Default::default()
         },
         job: vec![Job {
             name: "name".to_string(),
@@ -202,9 +248,13 @@ fn test_long_job_tcp() {
             output: vec![Output {
                 name: "name".to_string(),
                 value: "$1".to_string(),
+                ..Default::default()
             }],
+            ..Default::default()
         }],
-    });
+        ..Default::default()
+    })
+    .unwrap();
 
     regite.start();
     thread::sleep(Duration::from_secs(5));
@@ -220,4 +270,373 @@ fn test_long_job_tcp() {
 
     // there should have been a third run in progress
     assert_eq!(3, counter.load(Ordering::SeqCst));
+    assert_eq!(1, connections.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_timed_out_job() {
+    let (counter, address) = create_listener();
+    let mut regite = Regite::new(Config {
+        general: General {
+            prefix: "prefix".to_string(),
+            hostname: Some("host".to_string()),
+            graphite_connection_type: GraphiteConnectionType::Udp,
+            graphite_address: address,
+            ..Default::default()
+        },
+        job: vec![Job {
+            name: "name".to_string(),
+            interval: 1,
+            command: "/bin/bash -c \"sleep 10; echo 1\"".to_string(),
+            regex: "(.+)".to_string(),
+            output: vec![Output {
+                name: "name".to_string(),
+                value: "$1".to_string(),
+                ..Default::default()
+            }],
+            timeout: Some(1),
+            ..Default::default()
+        }],
+        ..Default::default()
+    })
+    .unwrap();
+
+    regite.start();
+    thread::sleep(Duration::from_secs(3));
+    regite.stop();
+    regite.join();
+
+    // the command is killed before it can print anything, and stopping isn't held up by it
+    assert_eq!(0, counter.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_batched_job() {
+    let socket = net::UdpSocket::bind("localhost:0").unwrap();
+    let mut regite = Regite::new(Config {
+        general: General {
+            prefix: "prefix".to_string(),
+            hostname: Some("host".to_string()),
+            graphite_connection_type: GraphiteConnectionType::Udp,
+            graphite_address: socket.local_addr().unwrap().to_string(),
+            ..Default::default()
+        },
+        job: vec![Job {
+            name: "name".to_string(),
+            interval: 1,
+            command: "/usr/bin/env printf \"1\\n2\\n3\\n\"".to_string(),
+            regex: "^(.+)$".to_string(),
+            output: vec![Output {
+                name: "line".to_string(),
+                value: "$1".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+        ..Default::default()
+    })
+    .unwrap();
+
+    let re = Regex::new("^prefix\\.host\\.line (\\d) \\d+$").unwrap();
+    let mut buf = [0; 100];
+
+    regite.start();
+    let len = socket.recv(&mut buf).unwrap();
+    regite.stop();
+    regite.join();
+
+    // all three lines from the run arrive in a single datagram
+    let msg = String::from_utf8_lossy(&buf[..len]);
+    let values: Vec<&str> = msg
+        .lines()
+        .map(|line| re.captures(line).unwrap().get(1).unwrap().as_str())
+        .collect();
+    assert_eq!(vec!["1", "2", "3"], values);
+}
+
+#[test]
+fn test_aligned_job() {
+    let socket = net::UdpSocket::bind("localhost:0").unwrap();
+    let mut regite = Regite::new(Config {
+        general: General {
+            prefix: "prefix".to_string(),
+            hostname: Some("host".to_string()),
+            graphite_connection_type: GraphiteConnectionType::Udp,
+            graphite_address: socket.local_addr().unwrap().to_string(),
+            ..Default::default()
+        },
+        job: vec![Job {
+            name: "name".to_string(),
+            interval: 2,
+            align: true,
+            command: "/bin/bash -c \"echo 1\"".to_string(),
+            regex: "(.+)".to_string(),
+            output: vec![Output {
+                name: "name".to_string(),
+                value: "$1".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+        ..Default::default()
+    })
+    .unwrap();
+
+    let re = Regex::new("([^ ]+) (\\d+) (\\d+)").unwrap();
+    let mut buf = [0; 100];
+
+    regite.start();
+    let mut timestamps = vec![];
+    for _ in 0..2 {
+        let len = socket.recv(&mut buf).unwrap();
+        let msg = String::from_utf8_lossy(&buf[..len]);
+        let captures = re.captures(&msg).unwrap();
+        timestamps.push(captures[3].parse::<u64>().unwrap());
+    }
+    regite.stop();
+    regite.join();
+
+    assert_eq!(0, timestamps[0] % 2);
+    assert_eq!(timestamps[0] + 2, timestamps[1]);
+}
+
+#[test]
+fn test_test_job() {
+    let config = Config {
+        general: General {
+            prefix: "prefix".to_string(),
+            hostname: Some("host".to_string()),
+            ..Default::default()
+        },
+        job: vec![Job {
+            name: "name".to_string(),
+            interval: 1,
+            command: "/bin/bash -c \"echo 1; echo x\"".to_string(),
+            regex: "^(.+)$".to_string(),
+            output: vec![Output {
+                name: "name".to_string(),
+                value: "$1".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    let matches = Regite::test_job(&config, "name", None).unwrap();
+    assert_eq!(2, matches.len());
+    assert!(matches[0].lines[0].starts_with("prefix.host.name 1 "));
+    assert!(matches[1].lines.is_empty());
+
+    let matches = Regite::test_job(&config, "name", Some("2")).unwrap();
+    assert!(matches[0].lines[0].starts_with("prefix.host.name 2 "));
+
+    assert!(Regite::test_job(&config, "missing", None).is_err());
+}
+
+#[test]
+fn test_run_once() {
+    let (counter, address) = create_listener();
+    let job = |name: &str, command: &str| Job {
+        name: name.to_string(),
+        interval: 60,
+        command: command.to_string(),
+        regex: "^(.+)$".to_string(),
+        output: vec![Output {
+            name: "name".to_string(),
+            value: "$1".to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let config = Config {
+        general: General {
+            hostname: Some("host".to_string()),
+            graphite_connection_type: GraphiteConnectionType::Udp,
+            graphite_address: address,
+            ..Default::default()
+        },
+        job: vec![job("ok", "echo 1"), job("failing", "false")],
+        ..Default::default()
+    };
+
+    assert!(Regite::run_once(&config, &["ok".to_string()]).is_ok());
+    assert!(Regite::run_once(&config, &[]).is_err());
+    assert!(Regite::run_once(&config, &["missing".to_string()]).is_err());
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(2, counter.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_reload() {
+    let (lines, address) = create_recording_listener();
+    let general = General {
+        prefix: "prefix".to_string(),
+        hostname: Some("host".to_string()),
+        graphite_connection_type: GraphiteConnectionType::Udp,
+        graphite_address: address,
+        ..Default::default()
+    };
+    let job = |name: &str| Job {
+        name: name.to_string(),
+        interval: 1,
+        command: "echo 1".to_string(),
+        regex: "^(.+)$".to_string(),
+        output: vec![Output {
+            name: name.to_string(),
+            value: "$1".to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    let mut regite = Regite::new(Config {
+        general: general.clone(),
+        job: vec![job("a"), job("b")],
+        ..Default::default()
+    })
+    .unwrap();
+    regite.start();
+    thread::sleep(Duration::from_millis(1500));
+
+    let mut invalid = job("c");
+    invalid.regex = "(".to_string();
+    assert!(regite
+        .reload(Config {
+            general: general.clone(),
+            job: vec![job("a"), invalid],
+            ..Default::default()
+        })
+        .is_err());
+    thread::sleep(Duration::from_millis(1500));
+    {
+        let lines = lines.lock().unwrap();
+        assert!(lines.iter().any(|line| line.starts_with("prefix.host.b ")));
+        assert!(!lines.iter().any(|line| line.starts_with("prefix.host.c ")));
+    }
+
+    regite
+        .reload(Config {
+            general,
+            job: vec![job("a"), job("c")],
+            ..Default::default()
+        })
+        .unwrap();
+    // a run of b can still arrive late, but none can have started after the reload returned
+    let reloaded = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    thread::sleep(Duration::from_millis(2500));
+    regite.stop();
+    regite.join();
+
+    let lines = lines.lock().unwrap();
+    let after_reload = |name: &str| {
+        lines.iter().any(|line| {
+            let mut fields = line.split(' ');
+            fields.next() == Some(name) && fields.nth(1).unwrap().parse::<u64>().unwrap() > reloaded
+        })
+    };
+    assert!(after_reload("prefix.host.a"));
+    assert!(!after_reload("prefix.host.b"));
+    assert!(after_reload("prefix.host.c"));
+}
+
+#[test]
+fn test_job_overrides() {
+    let (host_lines, host_address) = create_recording_listener();
+    let (app_lines, app_address) = create_recording_listener();
+    let job = |name: &str| Job {
+        name: name.to_string(),
+        interval: 60,
+        command: "echo 1".to_string(),
+        regex: "^(.+)$".to_string(),
+        output: vec![Output {
+            name: name.to_string(),
+            value: "$1".to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let config = Config {
+        general: General {
+            prefix: "prefix".to_string(),
+            hostname: Some("host".to_string()),
+            graphite_connection_type: GraphiteConnectionType::Udp,
+            graphite_address: host_address,
+            ..Default::default()
+        },
+        job: vec![
+            job("host"),
+            Job {
+                prefix: Some("apps".to_string()),
+                omit_hostname: true,
+                graphite_address: Some(app_address),
+                ..job("app")
+            },
+        ],
+        ..Default::default()
+    };
+
+    Regite::run_once(&config, &[]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let host_lines = host_lines.lock().unwrap();
+    assert_eq!(1, host_lines.len());
+    assert!(host_lines[0].starts_with("prefix.host.host 1 "));
+    let app_lines = app_lines.lock().unwrap();
+    assert_eq!(1, app_lines.len());
+    assert!(app_lines[0].starts_with("apps.app 1 "));
+}
+
+#[test]
+fn test_backends() {
+    let (old_lines, old_address) = create_recording_listener();
+    let (new_lines, new_address) = create_recording_listener();
+    let closed_address = net::TcpListener::bind("localhost:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let backend = |connection_type, address: &str| Destination {
+        connection_type,
+        address: address.to_string(),
+    };
+    let mut config = Config {
+        general: General {
+            prefix: "prefix".to_string(),
+            hostname: Some("host".to_string()),
+            ..Default::default()
+        },
+        backend: vec![
+            backend(GraphiteConnectionType::Udp, &old_address),
+            backend(GraphiteConnectionType::Udp, &new_address),
+        ],
+        job: vec![Job {
+            name: "name".to_string(),
+            interval: 60,
+            command: "echo 1".to_string(),
+            regex: "^(.+)$".to_string(),
+            output: vec![Output {
+                name: "name".to_string(),
+                value: "$1".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    Regite::run_once(&config, &[]).unwrap();
+
+    config
+        .backend
+        .push(backend(GraphiteConnectionType::Tcp, &closed_address));
+    assert!(Regite::run_once(&config, &[]).is_err());
+    thread::sleep(Duration::from_millis(100));
+
+    assert_eq!(2, old_lines.lock().unwrap().len());
+    assert_eq!(2, new_lines.lock().unwrap().len());
 }